@@ -0,0 +1,327 @@
+//! The libgit2 operations a sync is made of.
+
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use crate::repository::{Credentials, GitResult};
+use crate::signature;
+
+static FETCH_HEAD: &str = "FETCH_HEAD";
+
+/// A file the status lists, relative to the root of the working tree.
+pub struct FileStatus {
+    pub status: git2::Status,
+    pub path: PathBuf,
+    /// The previous path of a renamed file.
+    pub old_path: Option<PathBuf>,
+}
+
+/// The status, staging, commits, fetches, merges and pushes of a sync on
+/// top of libgit2.
+pub struct Git2Backend {
+    repo: git2::Repository,
+}
+
+impl Git2Backend {
+    pub fn open(path: &str) -> GitResult<Self> {
+        Ok(Self {
+            repo: git2::Repository::open(path)?,
+        })
+    }
+
+    pub fn init(path: &str) -> GitResult<Self> {
+        Ok(Self {
+            repo: git2::Repository::init(path)?,
+        })
+    }
+
+    /// The repository, for everything the backend doesn't cover.
+    pub fn repository(&self) -> &git2::Repository {
+        &self.repo
+    }
+
+    pub fn repository_mut(&mut self) -> &mut git2::Repository {
+        &mut self.repo
+    }
+
+    /// The changed and untracked files of the working tree. Renames are
+    /// detected and listed as one entry with both paths.
+    pub fn status(&self) -> GitResult<Vec<FileStatus>> {
+        let mut files = Vec::new();
+        for entry in self.repo.statuses(Some(&mut status_options()))?.iter() {
+            let status = entry.status();
+            // The path of an entry is the old one for a rename.
+            let (path, old_path) = match entry.index_to_workdir() {
+                Some(delta) if status.contains(git2::Status::WT_RENAMED) => (
+                    delta.new_file().path().and_then(Path::to_str),
+                    Some(delta.old_file().path().and_then(Path::to_str)),
+                ),
+                _ => (entry.path(), None),
+            };
+            let file = index_path(path).and_then(|path| {
+                Ok(FileStatus {
+                    status,
+                    path,
+                    old_path: old_path.map(index_path).transpose()?,
+                })
+            });
+            match file {
+                Ok(file) => files.push(file),
+                // The index can't take such a path, it shouldn't stop the
+                // sync of all other files.
+                Err(e) => warn!(
+                    "Not syncing {}: {}",
+                    String::from_utf8_lossy(entry.path_bytes()),
+                    e.message()
+                ),
+            }
+        }
+        Ok(files)
+    }
+
+    /// Removes `remove` from the index and adds `add` to it, in that order.
+    pub fn stage(&self, add: &[&Path], remove: &[&Path]) -> GitResult<()> {
+        // Everything is staged on one index and written once, so e.g.
+        // deleting a whole directory removes all of its files at once.
+        let mut index = self.repo.index()?;
+        for path in remove {
+            index.remove_path(path)?;
+        }
+        for path in add {
+            index.add_path(path)?;
+        }
+        index.write()
+    }
+
+    /// Commits the index on top of HEAD and moves HEAD to the new commit,
+    /// which is signed if a `signing_key` is given.
+    pub fn commit(
+        &self,
+        author: &git2::Signature,
+        committer: &git2::Signature,
+        message: &str,
+        signing_key: Option<&str>,
+    ) -> GitResult<git2::Oid> {
+        let update_ref = "HEAD";
+        let mut index = self.repo.index()?;
+        // A hook may have staged more changes.
+        index.read(false)?;
+        let tree_oid = index.write_tree()?;
+        let tree = self.repo.find_tree(tree_oid)?;
+
+        info!(
+            "New commit: {}, {}, {}, {}",
+            update_ref, author, committer, message
+        );
+
+        let commits = match self.repo.head() {
+            Ok(r) => vec![r.peel_to_commit()?],
+            // HEAD does not Exist; Return a vector without any commits
+            Err(_) => Vec::new(),
+        };
+        let parents = commits.iter().collect::<Vec<_>>();
+
+        let signing_key = match signing_key {
+            Some(key) => key,
+            None => {
+                return self.repo.commit(
+                    Some(update_ref),
+                    author,
+                    committer,
+                    message,
+                    &tree,
+                    &parents,
+                )
+            }
+        };
+
+        info!("Signing commit with key {}", signing_key);
+        let buffer = self
+            .repo
+            .commit_create_buffer(author, committer, message, &tree, &parents)?;
+        let content = buffer
+            .as_str()
+            .ok_or_else(|| git2::Error::from_str("the commit to sign is not valid UTF-8"))?;
+        let gpg_signature = signature::sign(content.as_bytes(), signing_key)?;
+        let oid = self.repo.commit_signed(content, &gpg_signature, None)?;
+
+        // commit_signed only writes the object, move HEAD like commit does.
+        let head = self.repo.find_reference(update_ref)?;
+        match head.symbolic_target() {
+            Some(branch) => {
+                self.repo
+                    .reference(branch, oid, true, &format!("commit: {}", message))?;
+            }
+            None => self.repo.set_head_detached(oid)?,
+        }
+        Ok(oid)
+    }
+
+    /// Fetches `refspecs` from `remote` and returns the commit fetched for
+    /// `branch`, `None` if nothing was fetched, e.g. because the remote
+    /// doesn't have `branch` yet. Fails if only other refs were fetched.
+    pub fn fetch(
+        &self,
+        remote: &str,
+        refspecs: &[&str],
+        branch: &str,
+        credentials: &Credentials,
+    ) -> GitResult<Option<git2::Oid>> {
+        let mut remote = self.repo.find_remote(remote)?;
+        let mut fetch_options = git2::FetchOptions::new();
+        fetch_options.remote_callbacks(credentials.callbacks());
+        //fetch_options.download_tags(git2::AutotagOption::All);
+        info!(
+            "Fetching {} from {}",
+            refspecs.join(" "),
+            remote.name().unwrap_or_default()
+        );
+        remote.fetch(refspecs, Some(&mut fetch_options), None)?;
+        self.fetched_commit(branch)
+    }
+
+    /// Merges `remote` into `local`, the commit at HEAD, updates the working
+    /// tree and moves HEAD to the merge commit.
+    ///
+    /// Returns `None` if the merge stopped because of conflicts, which are
+    /// left in the working tree.
+    pub fn merge(
+        &self,
+        local: git2::Oid,
+        remote: git2::Oid,
+        message: &str,
+        signature: &git2::Signature,
+    ) -> GitResult<Option<git2::Oid>> {
+        let local_commit = self.repo.find_commit(local)?;
+        let remote_commit = self.repo.find_commit(remote)?;
+        let ancestor = self
+            .repo
+            .find_commit(self.repo.merge_base(local, remote)?)?
+            .tree()?;
+        let mut idx = self.repo.merge_trees(
+            &ancestor,
+            &local_commit.tree()?,
+            &remote_commit.tree()?,
+            None,
+        )?;
+
+        if idx.has_conflicts() {
+            info!("Merge conficts detected...");
+            self.repo.checkout_index(Some(&mut idx), None)?;
+            return Ok(None);
+        }
+        let result_tree = self.repo.find_tree(idx.write_tree_to(&self.repo)?)?;
+        // now create the merge commit
+        let merge_oid = self.repo.commit(
+            None,
+            signature,
+            signature,
+            message,
+            &result_tree,
+            &[&local_commit, &remote_commit],
+        )?;
+        // Update the working tree while HEAD still points to the local
+        // commit, so the checkout compares against it and brings in the
+        // remote changes. It keeps uncommitted changes and fails instead of
+        // overwriting them.
+        self.repo.checkout_tree(result_tree.as_object(), None)?;
+        self.repo
+            .head()?
+            .set_target(merge_oid, &format!("merge: {}", message))?;
+        Ok(Some(merge_oid))
+    }
+
+    /// Pushes `refspecs` to `remote`. A reference the remote rejects fails
+    /// the push, with `ErrorCode::NotFastForward` if it has newer commits.
+    pub fn push(
+        &self,
+        remote: &str,
+        refspecs: &[String],
+        credentials: &Credentials,
+    ) -> GitResult<()> {
+        let mut remote = self.repo.find_remote(remote)?;
+        // The remote reports rejected references through the callback, the
+        // push itself still succeeds.
+        let rejection = RefCell::new(None);
+        let mut callbacks = credentials.callbacks();
+        callbacks.push_update_reference(|refname, status| {
+            if let Some(status) = status {
+                *rejection.borrow_mut() = Some(format!("{} was rejected: {}", refname, status));
+            }
+            Ok(())
+        });
+        let mut push_options = git2::PushOptions::new();
+        push_options.remote_callbacks(callbacks);
+        remote.push(refspecs, Some(&mut push_options))?;
+        drop(push_options);
+
+        match rejection.into_inner() {
+            Some(msg) => {
+                // Servers word it differently, e.g. "fetch first" or
+                // "non-fast-forward".
+                let code = if msg.contains("fast-forward")
+                    || msg.contains("fastforward")
+                    || msg.contains("fetch first")
+                {
+                    git2::ErrorCode::NotFastForward
+                } else {
+                    git2::ErrorCode::GenericError
+                };
+                Err(git2::Error::new(code, git2::ErrorClass::Reference, msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// The commit FETCH_HEAD lists for `branch`, `None` if it's empty.
+    ///
+    /// Every line of FETCH_HEAD is the fetched id, a tab, `not-for-merge`
+    /// or nothing, a tab and a description like `branch 'main' of <url>`.
+    fn fetched_commit(&self, branch: &str) -> GitResult<Option<git2::Oid>> {
+        let path = self.repo.path().join(FETCH_HEAD);
+        let content = std::fs::read_to_string(&path).map_err(|e| {
+            git2::Error::from_str(&format!("failed to read {}: {}", path.display(), e))
+        })?;
+        if content.trim().is_empty() {
+            return Ok(None);
+        }
+        // Merging another ref that happened to be fetched would mix it into
+        // the synced branch.
+        let wanted = format!("\tbranch '{}' of ", branch);
+        let line = content
+            .lines()
+            .find(|line| line.contains(&wanted))
+            .ok_or_else(|| {
+                git2::Error::new(
+                    git2::ErrorCode::NotFound,
+                    git2::ErrorClass::Reference,
+                    format!("the fetched refs don't include the branch {}", branch),
+                )
+            })?;
+        git2::Oid::from_str(line.split('\t').next().unwrap_or_default()).map(Some)
+    }
+}
+
+/// The options for every status scan. Renames are detected, so they are
+/// reported as one `WT_RENAMED` entry with the old and new path instead of a
+/// deletion and an unrelated new file.
+fn status_options() -> git2::StatusOptions {
+    let mut status_options = git2::StatusOptions::new();
+    status_options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .renames_head_to_index(true)
+        .renames_index_to_workdir(true);
+    status_options
+}
+
+/// Turns a path reported by git2 into the form the index expects: relative
+/// to the repository root and separated by forward slashes on every platform.
+pub fn index_path(path: Option<&str>) -> GitResult<PathBuf> {
+    let path = path.ok_or_else(|| git2::Error::from_str("Path is not valid UTF-8"))?;
+
+    #[cfg(windows)]
+    let path = path.replace('\\', "/");
+
+    Ok(PathBuf::from(path.trim_start_matches("./")))
+}
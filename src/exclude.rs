@@ -0,0 +1,45 @@
+//! Exclusions from sync commits listed in `.git-sync-ignore`.
+
+use ignore::gitignore::Gitignore;
+use std::path::Path;
+
+/// The file in the root of the working tree listing the exclusions.
+pub static EXCLUDE_FILE: &str = ".git-sync-ignore";
+
+/// Paths git-sync doesn't commit, even if git tracks them.
+///
+/// The patterns use the `.gitignore` syntax and are matched relative to the
+/// root of the working tree. A missing file excludes nothing.
+pub struct Excludes {
+    matcher: Gitignore,
+}
+
+impl Excludes {
+    pub fn load(workdir: &Path) -> Self {
+        let path = workdir.join(EXCLUDE_FILE);
+        if !path.exists() {
+            return Self {
+                matcher: Gitignore::empty(),
+            };
+        }
+
+        let (matcher, error) = Gitignore::new(&path);
+        if let Some(e) = error {
+            warn!("Skipping invalid patterns in {}: {}", path.display(), e);
+        }
+        info!(
+            "Excluding {} pattern(s) of {} from sync commits",
+            matcher.num_ignores(),
+            path.display()
+        );
+        Self { matcher }
+    }
+
+    /// Whether `path`, relative to the working tree, or one of its parent
+    /// directories is excluded.
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(path, false)
+            .is_ignore()
+    }
+}
@@ -0,0 +1,62 @@
+//! Creating the remote repository on a forge like GitHub or Gitea.
+
+use std::str::FromStr;
+
+use crate::GitSyncError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Forge {
+    GitHub,
+    Gitea,
+}
+
+impl FromStr for Forge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "github" => Ok(Forge::GitHub),
+            "gitea" => Ok(Forge::Gitea),
+            _ => Err(format!("unknown forge \"{}\"", s)),
+        }
+    }
+}
+
+impl Forge {
+    fn create_repo_url(self, host: Option<&str>) -> Result<String, GitSyncError> {
+        match (self, host) {
+            (Forge::GitHub, None) => Ok("https://api.github.com/user/repos".to_owned()),
+            // GitHub Enterprise serves the REST API below /api/v3
+            (Forge::GitHub, Some(host)) => Ok(format!("https://{}/api/v3/user/repos", host)),
+            (Forge::Gitea, Some(host)) => Ok(format!("https://{}/api/v1/user/repos", host)),
+            (Forge::Gitea, None) => Err(GitSyncError::Forge(
+                "Gitea needs a host to create the repository on".to_owned(),
+            )),
+        }
+    }
+}
+
+/// Creates a private repository called `name` for the user owning `token`.
+///
+/// Returns the SSH URL of the new repository.
+pub fn create_repository(
+    forge: Forge,
+    host: Option<&str>,
+    token: &str,
+    name: &str,
+) -> Result<String, GitSyncError> {
+    let url = forge.create_repo_url(host)?;
+    info!("Creating repository {} at {}", name, url);
+
+    let response = ureq::post(&url)
+        .set("Authorization", &format!("token {}", token))
+        .set("Accept", "application/json")
+        .send_json(serde_json::json!({ "name": name, "private": true }))
+        .map_err(|e| GitSyncError::Forge(e.to_string()))?;
+    let body: serde_json::Value = response.into_json()?;
+
+    body["ssh_url"]
+        .as_str()
+        .map(str::to_owned)
+        .ok_or_else(|| GitSyncError::Forge("the response contains no ssh_url".to_owned()))
+}
@@ -0,0 +1,64 @@
+//! Running git hooks, which libgit2 doesn't do on its own.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::repository::GitResult;
+
+/// The directory git looks for hooks in.
+///
+/// `core.hooksPath` replaces the default `hooks` directory inside `.git`,
+/// a relative path is resolved against the root of the working tree like
+/// git does, e.g. for hooks managed by `pre-commit` or `husky`.
+pub fn hooks_dir(repo: &git2::Repository) -> GitResult<PathBuf> {
+    let config = repo.config()?.snapshot()?;
+    match config.get_path("core.hooksPath") {
+        Ok(path) if path.is_absolute() => Ok(path),
+        Ok(path) => Ok(repo.workdir().unwrap_or_else(|| repo.path()).join(path)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(repo.path().join("hooks")),
+        Err(e) => Err(e),
+    }
+}
+
+/// Runs the hook `name` in the root of the working tree and fails if it
+/// exits unsuccessfully. A missing or non-executable hook is skipped, just
+/// like git does.
+pub fn run(repo: &git2::Repository, name: &str, args: &[&str]) -> GitResult<()> {
+    let hook = hooks_dir(repo)?.join(name);
+    if !is_executable(&hook) {
+        debug!("No {} hook at {}", name, hook.display());
+        return Ok(());
+    }
+
+    info!("Running the {} hook", name);
+    let status = Command::new(&hook)
+        .args(args)
+        .current_dir(repo.workdir().unwrap_or_else(|| repo.path()))
+        .status()
+        .map_err(|e| hook_error(format!("failed to run the {} hook: {}", name, e)))?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(hook_error(format!(
+            "the {} hook failed with {}",
+            name, status
+        )))
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata()
+        .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+fn hook_error(msg: String) -> git2::Error {
+    git2::Error::new(git2::ErrorCode::User, git2::ErrorClass::Callback, msg)
+}
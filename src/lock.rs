@@ -0,0 +1,43 @@
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+use crate::GitSyncError;
+
+static LOCK_FILE: &str = "git-sync.lock";
+
+/// An exclusive lock on a repository, held for as long as the value lives.
+///
+/// The lock is taken on `.git/git-sync.lock`, so two git-sync instances
+/// working on the same repository can't race on the index or the push.
+pub struct RepoLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl RepoLock {
+    pub fn acquire(git_dir: &Path) -> Result<Self, GitSyncError> {
+        let path = git_dir.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        file.try_lock_exclusive()
+            .map_err(|_| GitSyncError::AlreadyRunning(path.display().to_string()))?;
+        debug!("Acquired lock {}", path.display());
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for RepoLock {
+    fn drop(&mut self) {
+        // The lock file itself is left in place; removing it would let a
+        // waiting instance lock an unlinked file.
+        match FileExt::unlock(&self.file) {
+            Ok(()) => debug!("Released lock {}", self.path.display()),
+            Err(e) => warn!("Failed to release lock {}: {}", self.path.display(), e),
+        }
+    }
+}
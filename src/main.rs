@@ -2,36 +2,98 @@
 extern crate log;
 
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
-use git2::{Config, ConfigLevel, Repository, Status, StatusEntry};
-use notify::{watcher, RecursiveMode, Watcher};
-use std::path::Path;
-use std::sync::mpsc::channel;
-use std::time::Duration;
+use env_logger::WriteStyle;
+use git2::{Config, ConfigLevel, Repository, Status};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use log::LevelFilter;
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
 static PROG_NAME: &str = "git-sync";
+/// How often to look for a watched repository that disappeared.
+static REAPPEAR_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+mod backend;
+use backend::{index_path, FileStatus};
+
+mod exclude;
+#[cfg(feature = "forge")]
+mod forge;
+use exclude::{Excludes, EXCLUDE_FILE};
+
+mod hooks;
+mod lock;
+use lock::RepoLock;
+
+mod pidfile;
+use pidfile::PidFile;
 
 mod repository;
-use repository::RepoInformation;
+mod shutdown;
+mod signature;
+mod systemd;
+#[cfg(test)]
+mod testutil;
+mod throttle;
+use repository::{
+    current_branch, is_stale, is_transient, remote_address, Credentials, MergeMode,
+    RepoInformation, SyncOptions,
+};
+use throttle::EventLimit;
 
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum GitSyncError {
-    #[error("git2 error occured")]
+    #[error("git2 error occured: {0}")]
     Git2(#[from] git2::Error),
     #[error("An IO error ouccred")]
     Io(#[from] std::io::Error),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    #[error("another git-sync instance is already running (lock {0} is held)")]
+    AlreadyRunning(String),
+    #[error("another git-sync instance is already running with pid {1} (pid file {0})")]
+    PidFileInUse(String, u32),
+    #[error("forge error: {0}")]
+    Forge(String),
+    #[error("no {0} given and git-sync.{0} is not configured")]
+    MissingSetting(String),
+    #[error("no remote given, git-sync.remote is not configured and {0} has no upstream")]
+    MissingRemote(String),
+    #[error("{0} has a status git-sync can't commit: {1}")]
+    UnsupportedStatus(String, String),
+    #[error("invalid setting: {0}")]
+    InvalidSetting(String),
+    #[error("{0} check(s) failed")]
+    ChecksFailed(usize),
     #[error("unknown data store error")]
     Unknown,
 }
 
 fn main() {
-    let _ = env_logger::builder().is_test(true).try_init();
-    let matches = App::new(PROG_NAME)
+    let app = App::new(PROG_NAME)
         .version("0.1.0")
         .author("Olivier Lischer <olivier.lischer@liolin.ch>")
         .about("Check your git repo periodicaly for changes and syncs it to your remote")
         .setting(AppSettings::SubcommandRequired)
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Increases the log level from warn (-v info, -vv debug, -vvv trace)"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only logs errors, even with -v or RUST_LOG set"),
+        )
         .subcommand(
             SubCommand::with_name("setup")
                 .about("Initial setup routine")
@@ -61,11 +123,158 @@ fn main() {
                         .value_name("EMAIL")
                         .help("The email of the author in the commit message")
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("create-remote")
+                        .long("create-remote")
+                        .takes_value(true)
+                        .value_name("FORGE")
+                        .possible_values(&["github", "gitea"])
+                        .requires("forge-token")
+                        .help("Create the remote repository on a forge"),
+                )
+                .arg(
+                    Arg::with_name("forge-host")
+                        .long("forge-host")
+                        .takes_value(true)
+                        .value_name("HOST")
+                        .help("The host of the forge, required for gitea"),
+                )
+                .arg(
+                    Arg::with_name("forge-token")
+                        .long("forge-token")
+                        .takes_value(true)
+                        .value_name("TOKEN")
+                        .env("GIT_SYNC_FORGE_TOKEN")
+                        .hide_env_values(true)
+                        .help("The API token used to create the remote repository"),
+                )
+                .arg(
+                    Arg::with_name("repo-name")
+                        .long("repo-name")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("The name of the remote repository, defaults to the directory name"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .short("r")
+                        .long("remote")
+                        .takes_value(true)
+                        .value_name("REMOTE")
+                        .default_value("origin")
+                        .help("The name of the created remote"),
                 ),
         )
         .subcommand(
-            SubCommand::with_name("watch")
-                .about("Looks for changes in the repository")
+            SubCommand::with_name("config")
+                .about("Sets the configuration of a synced repository without committing")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("Sets the git repository")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("author")
+                        .short("a")
+                        .long("author")
+                        .takes_value(true)
+                        .value_name("AUTHOR")
+                        .help("The name of the author in the commit message"),
+                )
+                .arg(
+                    Arg::with_name("email")
+                        .short("e")
+                        .long("email")
+                        .takes_value(true)
+                        .value_name("EMAIL")
+                        .help("The email of the author in the commit message"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .short("r")
+                        .long("remote")
+                        .takes_value(true)
+                        .value_name("REMOTE")
+                        .help("The remote used when --remote is not given"),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .takes_value(true)
+                        .value_name("BRANCH")
+                        .help("The branch used when --branch is not given"),
+                )
+                .arg(
+                    Arg::with_name("remote-branch")
+                        .long("remote-branch")
+                        .takes_value(true)
+                        .value_name("BRANCH")
+                        .help("The remote branch used when --remote-branch is not given"),
+                )
+                .arg(
+                    Arg::with_name("remote-url")
+                        .long("remote-url")
+                        .takes_value(true)
+                        .value_name("URL")
+                        .help("The url used when --remote-url is not given"),
+                )
+                .arg(
+                    Arg::with_name("strategy")
+                        .long("strategy")
+                        .takes_value(true)
+                        .value_name("STRATEGY")
+                        .possible_values(&["ff", "ff-only", "no-ff"])
+                        .help("The merge strategy used when --ff-only or --no-ff is not given"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Shows the changes that are not synced yet")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("Sets the git repository")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .help("The name of the branch, defaults to the checked out branch")
+                        .takes_value(true)
+                        .value_name("BRANCH"),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .short("r")
+                        .long("remote")
+                        .help("The name of the remote, for example \"origin\", defaults to the upstream")
+                        .takes_value(true)
+                        .value_name("REMOTE"),
+                )
+                .arg(
+                    Arg::with_name("remote-branch")
+                        .long("remote-branch")
+                        .help(
+                            "The name of the branch on the remote, defaults to the upstream or \
+                             the local branch",
+                        )
+                        .takes_value(true)
+                        .value_name("BRANCH"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("doctor")
+                .about("Checks the configuration and the connection to the remote")
                 .arg(
                     Arg::with_name("directory")
                         .short("d")
@@ -79,36 +288,534 @@ fn main() {
                     Arg::with_name("branch")
                         .short("b")
                         .long("branch")
-                        .help("The name of the branch, for example \"master\"")
+                        .help("The name of the branch, defaults to the checked out branch")
                         .takes_value(true)
-                        .required(true)
                         .value_name("BRANCH"),
                 )
                 .arg(
                     Arg::with_name("remote")
                         .short("r")
                         .long("remote")
-                        .help("The name of the remote, for example \"origin\"")
+                        .help("The name of the remote, for example \"origin\", defaults to the upstream")
                         .takes_value(true)
-                        .required(true)
                         .value_name("REMOTE"),
+                )
+                .arg(
+                    Arg::with_name("ssh-key")
+                        .long("ssh-key")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Authenticate with this private key instead of the SSH agent"),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .long("token")
+                        .takes_value(true)
+                        .value_name("TOKEN")
+                        .env("GIT_SYNC_TOKEN")
+                        .hide_env_values(true)
+                        .conflicts_with("ssh-key")
+                        .help("Authenticate over HTTPS with this token"),
                 ),
         )
-        .get_matches();
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Looks for changes in the repository")
+                .arg(
+                    Arg::with_name("directory")
+                        .short("d")
+                        .long("directory")
+                        .value_name("DIR")
+                        .help("Sets the git repository")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("branch")
+                        .short("b")
+                        .long("branch")
+                        .help(
+                            "The name of the branch, defaults to the checked out branch. \
+                             Further branches that aren't checked out are fast-forwarded \
+                             or pushed",
+                        )
+                        .takes_value(true)
+                        .value_name("BRANCH")
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("remote")
+                        .short("r")
+                        .long("remote")
+                        .help("The name of the remote, for example \"origin\", defaults to the upstream")
+                        .takes_value(true)
+                        .value_name("REMOTE"),
+                )
+                .arg(
+                    Arg::with_name("remote-branch")
+                        .long("remote-branch")
+                        .help(
+                            "The name of the branch on the remote, defaults to the upstream or \
+                             the local branch",
+                        )
+                        .takes_value(true)
+                        .value_name("BRANCH"),
+                )
+                .arg(
+                    Arg::with_name("remote-url")
+                        .long("remote-url")
+                        .help("Create the remote with this url if it doesn't exist")
+                        .takes_value(true)
+                        .value_name("URL"),
+                )
+                .arg(
+                    Arg::with_name("fetch-refspec")
+                        .long("fetch-refspec")
+                        .help(
+                            "Fetch REFSPEC instead of the remote branch, which it has to \
+                             include, may be given more than once",
+                        )
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("REFSPEC"),
+                )
+                .arg(
+                    Arg::with_name("stash-before-merge")
+                        .long("stash-before-merge")
+                        .help("Stash local changes while fetching and merging, then restore them"),
+                )
+                .arg(
+                    Arg::with_name("ff-only")
+                        .long("ff-only")
+                        .help("Fail instead of creating a merge commit when the remote diverged"),
+                )
+                .arg(
+                    Arg::with_name("no-ff")
+                        .long("no-ff")
+                        .conflicts_with("ff-only")
+                        .help("Create a merge commit even when a fast-forward is possible"),
+                )
+                .arg(
+                    Arg::with_name("merge-message-template")
+                        .long("merge-message-template")
+                        .takes_value(true)
+                        .value_name("TEMPLATE")
+                        .help(
+                            "The message of merge commits, supports {remote}, {local}, \
+                             {branch} and {timestamp}",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("message-prefix")
+                        .long("message-prefix")
+                        .takes_value(true)
+                        .value_name("PREFIX")
+                        .help("Prepend PREFIX to every commit message, e.g. \"[auto]\""),
+                )
+                .arg(
+                    Arg::with_name("message")
+                        .long("message")
+                        .short("m")
+                        .takes_value(true)
+                        .value_name("MSG")
+                        .help(
+                            "Use MSG as the message of sync commits instead of describing \
+                             the changes, --message-prefix and --conventional are ignored",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("conventional")
+                        .long("conventional")
+                        .conflicts_with("message-prefix")
+                        .help(
+                            "Write Conventional Commits messages like \"chore(sync): ...\" \
+                             that list every changed file",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("conventional-type")
+                        .long("conventional-type")
+                        .takes_value(true)
+                        .value_name("TYPE")
+                        .requires("conventional")
+                        .validator(conventional_token)
+                        .help("The type of --conventional messages [default: chore]"),
+                )
+                .arg(
+                    Arg::with_name("conventional-scope")
+                        .long("conventional-scope")
+                        .takes_value(true)
+                        .value_name("SCOPE")
+                        .requires("conventional")
+                        .validator(|scope| match scope.as_str() {
+                            "" => Ok(()),
+                            scope => conventional_token(scope.to_owned()),
+                        })
+                        .help(
+                            "The scope of --conventional messages, none if empty \
+                             [default: sync]",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("print-commits")
+                        .long("print-commits")
+                        .help("Print the id and summary of every created commit"),
+                )
+                .arg(
+                    Arg::with_name("commit-per-dir")
+                        .long("commit-per-dir")
+                        .help("Make one commit per top-level directory instead of one for all changes"),
+                )
+                .arg(
+                    Arg::with_name("rollback-on-push-failure")
+                        .long("rollback-on-push-failure")
+                        .help("Undo the sync commit if the remote rejects the push"),
+                )
+                .arg(Arg::with_name("mirror").long("mirror").help(
+                    "Only force push all refs, deleting remote refs that don't exist \
+                             locally (destructive on the remote, like git push --mirror)",
+                ))
+                .arg(
+                    Arg::with_name("set-upstream")
+                        .long("set-upstream")
+                        .help("Replace the upstream of the branch with the synced remote branch"),
+                )
+                .arg(
+                    Arg::with_name("no-set-upstream")
+                        .long("no-set-upstream")
+                        .conflicts_with("set-upstream")
+                        .help("Never configure an upstream for the branch"),
+                )
+                .arg(
+                    Arg::with_name("verify-signatures")
+                        .long("verify-signatures")
+                        .help("Refuse to merge fetched commits without a good GPG signature"),
+                )
+                .arg(
+                    Arg::with_name("keyring")
+                        .long("keyring")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .requires("verify-signatures")
+                        .help("Verify signatures against this keyring instead of the default one"),
+                )
+                .arg(
+                    Arg::with_name("run-hooks")
+                        .long("run-hooks")
+                        .help("Run the pre-commit, post-commit and post-merge hooks"),
+                )
+                .arg(
+                    Arg::with_name("no-verify")
+                        .long("no-verify")
+                        .requires("run-hooks")
+                        .help("Skip the pre-commit hook, like git commit --no-verify"),
+                )
+                .arg(
+                    Arg::with_name("no-sign")
+                        .long("no-sign")
+                        .help("Don't sign sync commits, even if commit.gpgsign is set"),
+                )
+                .arg(
+                    Arg::with_name("author-from")
+                        .long("author-from")
+                        .takes_value(true)
+                        .value_name("SOURCE")
+                        .possible_values(&["config", "env", "last-commit"])
+                        .default_value("config")
+                        .help("Where the author of sync commits comes from"),
+                )
+                .arg(
+                    Arg::with_name("committer-name")
+                        .long("committer-name")
+                        .takes_value(true)
+                        .value_name("NAME")
+                        .help("The name of the committer, if it differs from the author"),
+                )
+                .arg(
+                    Arg::with_name("committer-email")
+                        .long("committer-email")
+                        .takes_value(true)
+                        .value_name("EMAIL")
+                        .help("The email of the committer, if it differs from the author"),
+                )
+                .arg(
+                    Arg::with_name("timezone")
+                        .long("timezone")
+                        .takes_value(true)
+                        .value_name("OFFSET")
+                        .allow_hyphen_values(true)
+                        .validator(|offset| parse_utc_offset(&offset).map(|_| ()))
+                        .help("Use a fixed UTC offset like \"+0200\" for commit timestamps"),
+                )
+                .arg(
+                    Arg::with_name("utc")
+                        .long("utc")
+                        .conflicts_with("timezone")
+                        .help("Use UTC for commit timestamps"),
+                )
+                .arg(
+                    Arg::with_name("ssh-key")
+                        .long("ssh-key")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Authenticate with this private key instead of the SSH agent"),
+                )
+                .arg(
+                    Arg::with_name("token")
+                        .long("token")
+                        .takes_value(true)
+                        .value_name("TOKEN")
+                        .env("GIT_SYNC_TOKEN")
+                        .hide_env_values(true)
+                        .conflicts_with("ssh-key")
+                        .help("Authenticate over HTTPS with this token"),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .takes_value(true)
+                        .value_name("SECONDS")
+                        .validator(|secs| {
+                            secs.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| format!("\"{}\" is not a number of seconds", secs))
+                        })
+                        .help("Also fetch and merge remote changes every SECONDS seconds"),
+                )
+                .arg(
+                    Arg::with_name("commit-only")
+                        .long("commit-only")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .value_name("GLOB")
+                        .validator(|pattern| {
+                            Glob::new(&pattern).map(|_| ()).map_err(|e| e.to_string())
+                        })
+                        .help("Only sync when a changed path matches GLOB, e.g. \"**/*.org\""),
+                )
+                .arg(
+                    Arg::with_name("push-retries")
+                        .long("push-retries")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("3")
+                        .validator(|n| {
+                            n.parse::<u32>()
+                                .map(|_| ())
+                                .map_err(|_| format!("\"{}\" is not a number", n))
+                        })
+                        .help("Merge and push again up to N times if the remote has new commits"),
+                )
+                .arg(
+                    Arg::with_name("push-retry-delay")
+                        .long("push-retry-delay")
+                        .takes_value(true)
+                        .value_name("MS")
+                        .default_value("1000")
+                        .validator(|ms| {
+                            ms.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| format!("\"{}\" is not a number of milliseconds", ms))
+                        })
+                        .help("Wait MS milliseconds before retrying a rejected push"),
+                )
+                .arg(
+                    Arg::with_name("tag-on-sync")
+                        .long("tag-on-sync")
+                        .takes_value(true)
+                        .value_name("PATTERN")
+                        .validator(|pattern| {
+                            // Checked with the placeholders filled in.
+                            let name = pattern.replace("{timestamp}", "0").replace("{branch}", "b");
+                            if git2::Reference::is_valid_name(&format!("refs/tags/{}", name)) {
+                                Ok(())
+                            } else {
+                                Err(format!("\"{}\" is not a valid tag name", pattern))
+                            }
+                        })
+                        .help(
+                            "Tag every pushed commit as PATTERN, e.g. \"sync-{timestamp}\", \
+                             {branch} is replaced by the branch",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("tag-interval")
+                        .long("tag-interval")
+                        .takes_value(true)
+                        .value_name("SECS")
+                        .requires("tag-on-sync")
+                        .validator(|secs| {
+                            secs.parse::<u64>()
+                                .map(|_| ())
+                                .map_err(|_| format!("\"{}\" is not a number of seconds", secs))
+                        })
+                        .help("Create at most one tag every SECS seconds"),
+                )
+                .arg(
+                    Arg::with_name("push-tags")
+                        .long("push-tags")
+                        .requires("tag-on-sync")
+                        .help("Push the tags of --tag-on-sync"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help(
+                            "Print a diff of the changes a sync would commit instead of \
+                             committing, fetching or pushing",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("diff-preview-lines")
+                        .long("diff-preview-lines")
+                        .takes_value(true)
+                        .value_name("N")
+                        .default_value("200")
+                        .validator(|n| {
+                            n.parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|_| format!("\"{}\" is not a number", n))
+                        })
+                        .help("Print at most N lines of the --dry-run diff"),
+                )
+                .arg(
+                    Arg::with_name("min-changes")
+                        .long("min-changes")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|n| {
+                            n.parse::<usize>()
+                                .map(|_| ())
+                                .map_err(|_| format!("\"{}\" is not a number", n))
+                        })
+                        .help("Only commit once at least N files changed"),
+                )
+                .arg(
+                    Arg::with_name("max-commits")
+                        .long("max-commits")
+                        .takes_value(true)
+                        .value_name("N")
+                        .requires("max-commits-per")
+                        .validator(|count| match count.parse::<usize>() {
+                            Ok(count) if count > 0 => Ok(()),
+                            _ => Err(format!("\"{}\" is not a positive number", count)),
+                        })
+                        .help("Make at most N commits within --max-commits-per"),
+                )
+                .arg(
+                    Arg::with_name("max-commits-per")
+                        .long("max-commits-per")
+                        .takes_value(true)
+                        .value_name("SECS")
+                        .requires("max-commits")
+                        .validator(|secs| match secs.parse::<u64>() {
+                            Ok(secs) if secs > 0 => Ok(()),
+                            _ => Err(format!("\"{}\" is not a number of seconds", secs)),
+                        })
+                        .help(
+                            "Make at most --max-commits commits every SECS seconds, later \
+                             changes are committed together when older commits are SECS seconds old",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max-events-per-sec")
+                        .long("max-events-per-sec")
+                        .takes_value(true)
+                        .value_name("N")
+                        .validator(|rate| match rate.parse::<u32>() {
+                            Ok(rate) if rate > 0 => Ok(()),
+                            _ => Err(format!("\"{}\" is not a positive number", rate)),
+                        })
+                        .help(
+                            "Sync for at most N events per second, the changes of further \
+                             events are synced together later",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("pid-file")
+                        .long("pid-file")
+                        .value_name("PATH")
+                        .help("Write the process id to PATH while running")
+                        .takes_value(true),
+                ),
+        );
+
+    #[cfg(unix)]
+    let app = app.arg(
+        Arg::with_name("syslog")
+            .long("syslog")
+            .help("Send log records to syslog instead of stderr"),
+    );
+
+    let matches = app.get_matches();
+    init_logger(&matches);
 
-    match matches.subcommand() {
-        ("setup", Some(matches)) => run_setup(matches).unwrap(),
+    let result = match matches.subcommand() {
+        ("setup", Some(matches)) => run_setup(matches),
+        ("config", Some(matches)) => run_config(matches),
+        ("status", Some(matches)) => run_status(matches),
+        ("doctor", Some(matches)) => run_doctor(matches),
         ("watch", Some(matches)) => run_watch(matches),
         _ => unreachable!("The cli parser should prevent reaching here"),
+    };
+
+    if let Err(e) = result {
+        error!("{}", e);
+        std::process::exit(1);
     }
 }
 
-fn run_setup(matches: &ArgMatches) -> Result<(), GitSyncError> {
-    let dir = matches
-        .value_of("directory")
-        .expect("The cli parser should prevent reaching here");
+fn init_logger(matches: &ArgMatches) {
+    let quiet = matches.is_present("quiet");
+    let level = match matches.occurrences_of("verbose") {
+        _ if quiet => Some(LevelFilter::Error),
+        0 => None,
+        1 => Some(LevelFilter::Info),
+        2 => Some(LevelFilter::Debug),
+        _ => Some(LevelFilter::Trace),
+    };
 
-    let author = matches
+    #[cfg(unix)]
+    {
+        if matches.is_present("syslog") {
+            let level = level.unwrap_or(LevelFilter::Warn);
+            match syslog::init(syslog::Facility::LOG_DAEMON, level, Some(PROG_NAME)) {
+                Ok(()) => return,
+                Err(e) => eprintln!("{}: could not connect to syslog: {}", PROG_NAME, e),
+            }
+        }
+    }
+
+    // RUST_LOG may enable more than the level for some modules.
+    let env = if quiet {
+        env_logger::Env::new().write_style(env_logger::DEFAULT_WRITE_STYLE_ENV)
+    } else {
+        env_logger::Env::default()
+    };
+    let mut builder = env_logger::Builder::from_env(env);
+    // The levels are colored if stderr is a terminal, unless NO_COLOR asks
+    // for plain output, see https://no-color.org.
+    if std::env::var_os("NO_COLOR").map_or(false, |value| !value.is_empty()) {
+        builder.write_style(WriteStyle::Never);
+    }
+    // Without -v, RUST_LOG decides and warnings are shown if it isn't set.
+    if let Some(level) = level {
+        builder.filter_level(level);
+    } else if std::env::var_os(env_logger::DEFAULT_FILTER_ENV).is_none() {
+        builder.filter_level(LevelFilter::Warn);
+    }
+    let _ = builder.try_init();
+}
+
+fn run_setup(matches: &ArgMatches) -> Result<(), GitSyncError> {
+    let dir = matches
+        .value_of("directory")
+        .expect("The cli parser should prevent reaching here");
+
+    let author = matches
         .value_of("author")
         .expect("The cli parser should prevent reaching here")
         .to_owned();
@@ -139,92 +846,1805 @@ fn run_setup(matches: &ArgMatches) -> Result<(), GitSyncError> {
     git_config.set_str("user.name", &author).unwrap();
     git_config.set_str("user.email", &email).unwrap();
 
+    if let Some(forge) = matches.value_of("create-remote") {
+        create_remote(matches, forge, &repo_information, dir)?;
+    }
+
     repo_information.commit("Initial commit")?;
     Ok(())
 }
 
-fn run_watch(matches: &ArgMatches) {
-    let dir = matches
-        .value_of("directory")
+#[cfg(feature = "forge")]
+fn create_remote(
+    matches: &ArgMatches,
+    forge: &str,
+    repo_information: &RepoInformation,
+    dir: &Path,
+) -> Result<(), GitSyncError> {
+    let forge: forge::Forge = forge.parse().map_err(GitSyncError::Forge)?;
+    let token = matches
+        .value_of("forge-token")
         .expect("The cli parser should prevent reaching here");
     let remote = matches
         .value_of("remote")
         .expect("The cli parser should prevent reaching here");
-    let branch = matches
-        .value_of("branch")
+    let name = match matches.value_of("repo-name") {
+        Some(name) => name.to_owned(),
+        None => dir
+            .canonicalize()?
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(str::to_owned)
+            .ok_or_else(|| {
+                GitSyncError::Forge("Can't derive a repository name from the directory".to_owned())
+            })?,
+    };
+
+    let url = forge::create_repository(forge, matches.value_of("forge-host"), token, &name)?;
+    info!("Adding remote {} with url {}", remote, url);
+    repo_information.git_repo().remote(remote, &url)?;
+    Ok(())
+}
+
+#[cfg(not(feature = "forge"))]
+fn create_remote(
+    _matches: &ArgMatches,
+    _forge: &str,
+    _repo_information: &RepoInformation,
+    _dir: &Path,
+) -> Result<(), GitSyncError> {
+    Err(GitSyncError::Forge(
+        "git-sync was built without the \"forge\" feature".to_owned(),
+    ))
+}
+
+fn run_config(matches: &ArgMatches) -> Result<(), GitSyncError> {
+    let dir = matches
+        .value_of("directory")
+        .expect("The cli parser should prevent reaching here");
+
+    let mut git_config = Repository::open(dir)?
+        .config()?
+        .open_level(ConfigLevel::Local)?;
+    let settings = [
+        ("author", "user.name"),
+        ("email", "user.email"),
+        ("remote", "git-sync.remote"),
+        ("branch", "git-sync.branch"),
+        ("remote-branch", "git-sync.remote-branch"),
+        ("remote-url", "git-sync.remote-url"),
+        ("strategy", "git-sync.strategy"),
+    ];
+
+    let mut changed = false;
+    for (arg, key) in settings.iter() {
+        if let Some(value) = matches.value_of(arg) {
+            info!("Setting {} to {}", key, value);
+            git_config.set_str(key, value)?;
+            changed = true;
+        }
+    }
+
+    if !changed {
+        let git_config = git_config.snapshot()?;
+        for (_, key) in settings.iter() {
+            if let Ok(value) = git_config.get_str(key) {
+                println!("{} = {}", key, value);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the value of the cli argument `name`, falling back to
+/// `git-sync.<name>` from the repository config.
+fn setting(
+    matches: &ArgMatches,
+    git_config: &Config,
+    name: &str,
+) -> Result<Option<String>, GitSyncError> {
+    if let Some(value) = matches.value_of(name) {
+        return Ok(Some(value.to_owned()));
+    }
+    match git_config.get_string(&format!("git-sync.{}", name)) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Like `setting` for the branch, but defaults to the checked out branch and
+/// fails if there is none.
+fn branch_setting(
+    matches: &ArgMatches,
+    git_config: &Config,
+    git_repo: &Repository,
+) -> Result<String, GitSyncError> {
+    match setting(matches, git_config, "branch")? {
+        Some(branch) => Ok(branch),
+        None => current_branch(git_repo)
+            .ok_or_else(|| GitSyncError::MissingSetting("branch".to_owned())),
+    }
+}
+
+/// Like `setting` for the remote, but defaults to the remote of the upstream
+/// of `branch`.
+fn remote_setting(
+    matches: &ArgMatches,
+    git_config: &Config,
+    branch: &str,
+) -> Result<Option<String>, GitSyncError> {
+    match setting(matches, git_config, "remote")? {
+        Some(remote) => Ok(Some(remote)),
+        None => Ok(upstream(git_config, branch).map(|(remote, _)| remote)),
+    }
+}
+
+/// Like `setting` for the remote branch, but defaults to the branch of the
+/// upstream of `branch` if it's on `remote`.
+fn remote_branch_setting(
+    matches: &ArgMatches,
+    git_config: &Config,
+    branch: &str,
+    remote: &str,
+) -> Result<Option<String>, GitSyncError> {
+    match setting(matches, git_config, "remote-branch")? {
+        Some(remote_branch) => Ok(Some(remote_branch)),
+        None => Ok(upstream(git_config, branch)
+            .filter(|(upstream_remote, _)| upstream_remote == remote)
+            .map(|(_, remote_branch)| remote_branch)),
+    }
+}
+
+/// The remote and the branch on it `branch` tracks, from `branch.<name>.remote`
+/// and `branch.<name>.merge`. An upstream in the repository itself, `.`, is
+/// not a remote to sync with.
+fn upstream(git_config: &Config, branch: &str) -> Option<(String, String)> {
+    let remote = git_config
+        .get_string(&format!("branch.{}.remote", branch))
+        .ok()?;
+    let merge = git_config
+        .get_string(&format!("branch.{}.merge", branch))
+        .ok()?;
+    if remote == "." {
+        return None;
+    }
+    let remote_branch = merge.trim_start_matches("refs/heads/").to_owned();
+    Some((remote, remote_branch))
+}
+
+fn run_status(matches: &ArgMatches) -> Result<(), GitSyncError> {
+    let dir = matches
+        .value_of("directory")
+        .expect("The cli parser should prevent reaching here");
+    let git_repo = Repository::open(dir)?;
+    let git_config = git_repo.config()?.snapshot()?;
+    let branch = branch_setting(matches, &git_config, &git_repo)?;
+    let remote = remote_setting(matches, &git_config, &branch)?
+        .ok_or_else(|| GitSyncError::MissingRemote(branch.clone()))?;
+    let remote_branch = remote_branch_setting(matches, &git_config, &branch, &remote)?;
+
+    let mut repo_information = RepoInformation::new(dir, &remote, &branch);
+    repo_information.set_options(SyncOptions {
+        remote_branch,
+        ..SyncOptions::default()
+    });
+    let excludes = Excludes::load(&workdir(&repo_information));
+    let changes = pending_changes(&repo_information, &excludes)?;
+    let unpushed = repo_information.unpushed_commits()?;
+
+    println!("{} uncommitted change(s)", changes);
+    println!(
+        "{} commit(s) not pushed to {}/{}",
+        unpushed,
+        remote,
+        repo_information.remote_branch()
+    );
+    Ok(())
+}
+
+/// Runs every check even if an earlier one failed, so all problems are
+/// reported at once. Checks that need a repository are skipped without one.
+fn run_doctor(matches: &ArgMatches) -> Result<(), GitSyncError> {
+    let dir = matches
+        .value_of("directory")
         .expect("The cli parser should prevent reaching here");
+    let mut failed = 0;
+    let mut check = |name: &str, result: Result<(), String>| match result {
+        Ok(()) => println!("ok    {}", name),
+        Err(reason) => {
+            println!("FAIL  {}: {}", name, reason);
+            failed += 1;
+        }
+    };
 
-    let repo_information = RepoInformation::new(dir, remote, branch);
+    let git_repo = match Repository::open(dir) {
+        Ok(repo) if repo.is_bare() => {
+            check("git repository", Err(format!("{} is bare", dir)));
+            return Err(GitSyncError::ChecksFailed(1));
+        }
+        Ok(repo) => {
+            check("git repository", Ok(()));
+            repo
+        }
+        Err(e) => {
+            check("git repository", Err(e.message().to_owned()));
+            return Err(GitSyncError::ChecksFailed(1));
+        }
+    };
+    let git_config = git_repo.config()?.snapshot()?;
+
+    for key in &["user.name", "user.email"] {
+        check(
+            key,
+            git_config
+                .get_string(key)
+                .map(|_| ())
+                .map_err(|_| "not configured".to_owned()),
+        );
+    }
+
+    let branch = branch_setting(matches, &git_config, &git_repo);
+    let mut remote = match &branch {
+        Ok(branch) => remote_setting(matches, &git_config, branch)?,
+        Err(_) => setting(matches, &git_config, "remote")?,
+    };
+    match &remote {
+        Some(name) => match git_repo.find_remote(name) {
+            Ok(found) => {
+                check(&format!("remote {}", name), Ok(()));
+                check(
+                    "remote url",
+                    match found.url() {
+                        Some(url)
+                            if remote_address(url).is_some() || local_remote_exists(dir, url) =>
+                        {
+                            Ok(())
+                        }
+                        Some(url) => Err(format!("{} is neither a network url nor a path", url)),
+                        None => Err("not valid UTF-8".to_owned()),
+                    },
+                );
+            }
+            Err(e) => {
+                check(&format!("remote {}", name), Err(e.message().to_owned()));
+                remote = None;
+            }
+        },
+        None => check(
+            "remote",
+            Err(match &branch {
+                Ok(branch) => GitSyncError::MissingRemote(branch.clone()).to_string(),
+                Err(_) => GitSyncError::MissingSetting("remote".to_owned()).to_string(),
+            }),
+        ),
+    }
+
+    match &branch {
+        Ok(name) => check(
+            &format!("branch {}", name),
+            git_repo
+                .find_branch(name, git2::BranchType::Local)
+                .map(|_| ())
+                .map_err(|e| e.message().to_owned()),
+        ),
+        Err(e) => check("branch", Err(e.to_string())),
+    }
+
+    // Without a remote there is nothing to authenticate against.
+    if let Some(remote) = &remote {
+        let branch = branch.as_deref().unwrap_or("");
+        let mut repo_information = RepoInformation::new(dir, remote, branch);
+        repo_information.set_options(SyncOptions {
+            credentials: credentials(matches),
+            ..SyncOptions::default()
+        });
+        check(
+            "authentication",
+            repo_information
+                .check_connection()
+                .map_err(|e| e.message().to_owned()),
+        );
+    }
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(GitSyncError::ChecksFailed(failed)),
+    }
+}
+
+fn credentials(matches: &ArgMatches) -> Credentials {
+    if let Some(key) = matches.value_of("ssh-key") {
+        Credentials::SshKey(PathBuf::from(key))
+    } else if let Some(token) = matches.value_of("token") {
+        Credentials::Token(token.to_owned())
+    } else {
+        Credentials::SshAgent
+    }
+}
 
+fn run_watch(matches: &ArgMatches) -> Result<(), GitSyncError> {
+    let dir = matches
+        .value_of("directory")
+        .expect("The cli parser should prevent reaching here");
+    let git_repo = Repository::open(dir)?;
+    let git_config = git_repo.config()?.snapshot()?;
+    let branch = branch_setting(matches, &git_config, &git_repo)?;
+    let remote = remote_setting(matches, &git_config, &branch)?
+        .ok_or_else(|| GitSyncError::MissingRemote(branch.clone()))?;
+    let remote_branch = remote_branch_setting(matches, &git_config, &branch, &remote)?;
+    let remote_url = setting(matches, &git_config, "remote-url")?;
+
+    let _pid_file = match matches.value_of("pid-file") {
+        Some(path) => Some(PidFile::create(Path::new(path))?),
+        None => None,
+    };
+
+    let mut repo_information = RepoInformation::new(dir, &remote, &branch);
+    let mut lock = Some(RepoLock::acquire(repo_information.git_repo().path())?);
+    let merge_mode = if matches.is_present("ff-only") {
+        MergeMode::FastForwardOnly
+    } else if matches.is_present("no-ff") {
+        MergeMode::NoFastForward
+    } else {
+        match git_config.get_str("git-sync.strategy") {
+            Ok(strategy) => strategy.parse().map_err(GitSyncError::InvalidSetting)?,
+            Err(_) => MergeMode::FastForward,
+        }
+    };
+    repo_information.set_options(SyncOptions {
+        stash_before_merge: matches.is_present("stash-before-merge"),
+        merge_mode,
+        committer_name: matches.value_of("committer-name").map(str::to_owned),
+        committer_email: matches.value_of("committer-email").map(str::to_owned),
+        utc_offset: if matches.is_present("utc") {
+            Some(0)
+        } else {
+            matches
+                .value_of("timezone")
+                .map(|offset| parse_utc_offset(offset).expect("Validated by the cli parser"))
+        },
+        commit_time: None,
+        credentials: credentials(matches),
+        merge_message_template: matches
+            .value_of("merge-message-template")
+            .map(str::to_owned),
+        message_prefix: matches.value_of("message-prefix").map(str::to_owned),
+        message: matches.value_of("message").map(str::to_owned),
+        conventional_type: matches.is_present("conventional").then(|| {
+            matches
+                .value_of("conventional-type")
+                .unwrap_or("chore")
+                .to_owned()
+        }),
+        conventional_scope: matches.is_present("conventional").then(|| {
+            matches
+                .value_of("conventional-scope")
+                .unwrap_or("sync")
+                .to_owned()
+        }),
+        print_commits: matches.is_present("print-commits"),
+        commit_per_dir: matches.is_present("commit-per-dir"),
+        rollback_on_push_failure: matches.is_present("rollback-on-push-failure"),
+        verify_signatures: matches.is_present("verify-signatures"),
+        keyring: matches.value_of("keyring").map(PathBuf::from),
+        remote_branch,
+        set_upstream: if matches.is_present("set-upstream") {
+            Some(true)
+        } else if matches.is_present("no-set-upstream") {
+            Some(false)
+        } else {
+            None
+        },
+        mirror: matches.is_present("mirror"),
+        dry_run: matches.is_present("dry-run"),
+        diff_preview_lines: matches
+            .value_of("diff-preview-lines")
+            .expect("The cli parser provides a default")
+            .parse()
+            .expect("Validated by the cli parser"),
+        author_from: matches
+            .value_of("author-from")
+            .expect("The cli parser provides a default")
+            .parse()
+            .expect("Validated by the cli parser"),
+        no_sign: matches.is_present("no-sign"),
+        run_hooks: matches.is_present("run-hooks"),
+        no_verify: matches.is_present("no-verify"),
+        push_retries: matches
+            .value_of("push-retries")
+            .expect("The cli parser provides a default")
+            .parse()
+            .expect("Validated by the cli parser"),
+        push_retry_delay: Duration::from_millis(
+            matches
+                .value_of("push-retry-delay")
+                .expect("The cli parser provides a default")
+                .parse()
+                .expect("Validated by the cli parser"),
+        ),
+        remote_url,
+        tag_pattern: matches.value_of("tag-on-sync").map(str::to_owned),
+        tag_interval: matches
+            .value_of("tag-interval")
+            .map(|secs| Duration::from_secs(secs.parse().expect("Validated by the cli parser"))),
+        push_tags: matches.is_present("push-tags"),
+        min_changes: matches
+            .value_of("min-changes")
+            .map(|n| n.parse().expect("Validated by the cli parser"))
+            .unwrap_or_default(),
+        max_commits: matches
+            .value_of("max-commits")
+            .map(|count| count.parse().expect("Validated by the cli parser")),
+        max_commits_per: matches
+            .value_of("max-commits-per")
+            .map(|secs| Duration::from_secs(secs.parse().expect("Validated by the cli parser"))),
+        fetch_refspecs: matches
+            .values_of("fetch-refspec")
+            .map(|refspecs| refspecs.map(str::to_owned).collect())
+            .unwrap_or_default(),
+        other_branches: matches
+            .values_of("branch")
+            .into_iter()
+            .flatten()
+            .skip(1)
+            .filter(|other| *other != branch)
+            .map(str::to_owned)
+            .collect(),
+    });
+
+    let mut excludes = Excludes::load(&workdir(&repo_information));
+    let commit_only = match matches.values_of("commit-only") {
+        Some(patterns) => {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(Glob::new(pattern).expect("Validated by the cli parser"));
+            }
+            Some(
+                builder
+                    .build()
+                    .map_err(|e| GitSyncError::InvalidSetting(e.to_string()))?,
+            )
+        }
+        None => None,
+    };
     let (tx, rx) = channel();
+    // The watcher spawns threads, so this goes first. Rescan only wakes the
+    // loop up, it stops before looking at the event.
+    let wake = tx.clone();
+    shutdown::on_signal(move || {
+        let _ = wake.send(DebouncedEvent::Rescan);
+    });
     let mut watcher = watcher(tx, Duration::from_millis(10)).unwrap();
-    watcher.watch(dir, RecursiveMode::Recursive).unwrap();
+    // Events have to use the same path as the working tree to be matched
+    // against it, and libgit2 resolves symlinks in that one.
+    let mut watched = watched_dir(dir);
+    watcher.watch(&watched, RecursiveMode::Recursive).unwrap();
+    systemd::notify_ready();
 
-    // TODO: Replace unwrap (Change Return type of function)
-    let commit = repo_information.fetch().unwrap();
-    repo_information.merge(commit).unwrap();
+    if !repo_information.options().mirror
+        && !repo_information.options().dry_run
+        && repo_information.is_online()
+    {
+        fetch_and_merge(&mut repo_information)?;
+    }
 
+    let interval = matches
+        .value_of("interval")
+        .map(|secs| Duration::from_secs(secs.parse().expect("Validated by the cli parser")));
+    let mut next_periodic_sync = interval.map(|interval| Instant::now() + interval);
+    let watchdog_interval = systemd::watchdog_interval();
+    let mut event_limit = matches
+        .value_of("max-events-per-sec")
+        .map(|rate| EventLimit::new(rate.parse().expect("Validated by the cli parser")));
+    // Set while the repository is gone, e.g. an unmounted volume.
+    let mut missing = false;
+    let mut resumed = false;
     loop {
-        match rx.recv() {
-            // TODO: Replace unwrap with proper error handeling
-            Ok(_) => update(&repo_information).unwrap(),
+        let until_periodic_sync =
+            next_periodic_sync.map(|next| next.saturating_duration_since(Instant::now()));
+        let until_window_reopens = repo_information.commit_window_reopens_in();
+        let until_deferred_sync = event_limit
+            .as_ref()
+            .and_then(EventLimit::until_deferred_sync);
+        let timeout = [
+            watchdog_interval,
+            until_periodic_sync,
+            until_deferred_sync,
+            until_window_reopens,
+            if missing {
+                Some(REAPPEAR_POLL_INTERVAL)
+            } else {
+                None
+            },
+        ]
+        .iter()
+        .flatten()
+        .min()
+        .copied();
+        let event = match timeout {
+            Some(timeout) => rx.recv_timeout(timeout),
+            None => rx.recv().map_err(RecvTimeoutError::from),
+        };
+        if shutdown::requested() {
+            info!("Stopping the watch of {}", dir);
+            break;
+        }
+
+        if missing {
+            if is_gone(dir) {
+                systemd::notify_watchdog();
+                continue;
+            }
+            info!("{} is back, resuming the sync", dir);
+            repo_information.reopen()?;
+            // A repository that was only moved away still has our lock.
+            drop(lock.take());
+            lock = Some(RepoLock::acquire(repo_information.git_repo().path())?);
+            let _ = watcher.unwatch(&watched);
+            watched = watched_dir(dir);
+            if let Err(e) = watcher.watch(&watched, RecursiveMode::Recursive) {
+                warn!("Failed to watch {} again: {}", dir, e);
+                continue;
+            }
+            excludes = Excludes::load(&workdir(&repo_information));
+            missing = false;
+            resumed = true;
+        }
+
+        let result = match event {
+            Ok(event) => {
+                if touches_exclude_file(&event) {
+                    excludes = Excludes::load(&workdir(&repo_information));
+                }
+                if !is_wanted(&event, commit_only.as_ref(), &workdir(&repo_information)) {
+                    trace!("Ignoring {:?}, it doesn't match --commit-only", event);
+                    Ok(())
+                } else if changes_content(&event) {
+                    if event_limit.as_mut().map_or(true, EventLimit::admit) {
+                        let rename = renamed_paths(&event, &workdir(&repo_information));
+                        sync(&mut repo_information, &excludes, rename, false)
+                    } else {
+                        trace!(
+                            "Deferring {:?}, there are more than {} events per second",
+                            event,
+                            event_limit.as_ref().map_or(0, EventLimit::rate)
+                        );
+                        Ok(())
+                    }
+                } else {
+                    trace!("Ignoring {:?}", event);
+                    Ok(())
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => Ok(()),
             Err(e) => {
                 debug!("Config watcher channel dropped unexpectedly: {}", e);
                 break;
             }
+        };
+        let periodic_sync_due = match next_periodic_sync {
+            Some(next) => Instant::now() >= next,
+            None => false,
+        };
+        let result = result.and_then(|()| {
+            if !event_limit
+                .as_mut()
+                .map_or(false, EventLimit::deferred_sync_due)
+            {
+                return Ok(());
+            }
+            debug!("Syncing the changes of deferred events");
+            sync(&mut repo_information, &excludes, None, false)
+        });
+        // Commit the changes that were held back by the commit limit.
+        let result = result.and_then(|()| {
+            if until_window_reopens.is_none() || repo_information.commit_limit_reached() {
+                return Ok(());
+            }
+            debug!("The commit limit allows new commits again");
+            sync(&mut repo_information, &excludes, None, false)
+        });
+        // Catch up on everything that changed while the repository was gone.
+        let result = result.and_then(|()| {
+            if !periodic_sync_due && !resumed {
+                return Ok(());
+            }
+            resumed = false;
+            next_periodic_sync = interval.map(|interval| Instant::now() + interval);
+            sync(&mut repo_information, &excludes, None, true)
+        });
+
+        if let Err(e) = result {
+            if !is_gone(dir) {
+                return Err(e);
+            }
+            warn!(
+                "{} disappeared, pausing the sync until it's back: {}",
+                dir, e
+            );
+            missing = true;
+        }
+        systemd::notify_watchdog();
+    }
+    Ok(())
+}
+
+/// Whether `url` is a `file://` url or path of an existing repository, paths
+/// relative to the working tree `dir`.
+fn local_remote_exists(dir: &str, url: &str) -> bool {
+    let path = match url.strip_prefix("file://") {
+        Some(path) => path.strip_prefix("localhost").unwrap_or(path),
+        None => url,
+    };
+    Path::new(dir).join(path).exists()
+}
+
+/// `dir` with all symlinks resolved, as given if that fails.
+fn watched_dir(dir: &str) -> PathBuf {
+    std::fs::canonicalize(dir).unwrap_or_else(|_| PathBuf::from(dir))
+}
+
+/// Whether the repository at `dir` was removed or its volume unmounted.
+fn is_gone(dir: &str) -> bool {
+    !Path::new(dir).join(".git").exists()
+}
+
+/// Parses an UTC offset of the form `+HHMM`, `-HH:MM` or `Z` into minutes.
+fn parse_utc_offset(offset: &str) -> Result<i32, String> {
+    if offset == "Z" || offset.eq_ignore_ascii_case("utc") {
+        return Ok(0);
+    }
+
+    let invalid = || format!("\"{}\" is not an UTC offset like +0200", offset);
+    let (sign, rest) = match offset.chars().next() {
+        Some('+') => (1, &offset[1..]),
+        Some('-') => (-1, &offset[1..]),
+        _ => return Err(invalid()),
+    };
+    let digits = rest.replace(':', "");
+    if digits.len() != 4 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return Err(invalid());
+    }
+
+    let hours: i32 = digits[..2].parse().map_err(|_| invalid())?;
+    let minutes: i32 = digits[2..].parse().map_err(|_| invalid())?;
+    if hours > 14 || minutes > 59 {
+        return Err(invalid());
+    }
+    Ok(sign * (hours * 60 + minutes))
+}
+
+/// Runs `update`, and if it fails in a way that suggests the repository
+/// changed underneath the open handle, opens it again and retries once.
+fn sync(
+    repo_information: &mut RepoInformation,
+    excludes: &Excludes,
+    rename: Option<(PathBuf, PathBuf)>,
+    periodic: bool,
+) -> Result<(), GitSyncError> {
+    match update(repo_information, excludes, rename.clone(), periodic) {
+        Err(GitSyncError::Git2(e)) if is_stale(&e) => {
+            warn!(
+                "Re-opening {} after an error: {}",
+                repo_information.path(),
+                e.message()
+            );
+            repo_information.reopen()?;
+            update(repo_information, excludes, rename, periodic)
         }
+        result => result,
     }
 }
 
-fn update(repo_information: &RepoInformation) -> Result<(), GitSyncError> {
-    let statuses = repo_information.git_repo().statuses(None)?;
-    if statuses.is_empty() {
+/// Syncs the repository with the remote.
+///
+/// Event driven runs return early when there are no local changes, periodic
+/// runs always fetch and merge so remote changes show up in a clean working
+/// tree as well. Fewer changes than `min_changes`, or changes made after
+/// the commit limit was reached, are left for a later run. While the remote
+/// is unreachable, changes are only committed locally and all of them are
+/// pushed at once by the first run after it's back. Every run that fetches
+/// also syncs the other branches. In mirror mode, every run only pushes all
+/// refs, in dry-run mode it only previews the local changes.
+///
+/// `rename` is a rename reported by the watcher, which is staged as such
+/// before looking at the status.
+fn update(
+    repo_information: &mut RepoInformation,
+    excludes: &Excludes,
+    rename: Option<(PathBuf, PathBuf)>,
+    periodic: bool,
+) -> Result<(), GitSyncError> {
+    if repo_information.options().mirror {
+        if repo_information.is_online() {
+            repo_information.push()?;
+        } else {
+            info!("offline, deferring mirror push");
+        }
         return Ok(());
     }
 
-    let commit = repo_information.fetch()?;
-    repo_information.merge(commit)?;
+    let pending = pending_changes(repo_information, excludes)?;
+    let clean = pending == 0 && case_renames(repo_information.git_repo())?.is_empty();
+    if repo_information.options().dry_run {
+        if !clean {
+            print_diff_preview(repo_information, excludes)?;
+        }
+        return Ok(());
+    }
+    let min_changes = repo_information.options().min_changes;
+    let held = if clean {
+        false
+    } else if pending < min_changes {
+        debug!(
+            "{} of {} changes to commit, holding them back",
+            pending, min_changes
+        );
+        true
+    } else if repo_information.commit_limit_reached() {
+        info!(
+            "Made {} commits within {:?}, holding back the changes",
+            repo_information.options().max_commits.unwrap_or_default(),
+            repo_information
+                .options()
+                .max_commits_per
+                .unwrap_or_default()
+        );
+        true
+    } else {
+        false
+    };
+    let needs_push = (!clean && !held) || repo_information.unpushed_commits()? > 0;
+    if !needs_push && !periodic {
+        return Ok(());
+    }
 
-    let mut msg = String::new();
-    for s in repo_information.git_repo().statuses(None)?.iter() {
-        msg = match s.status() {
-            Status::WT_NEW | Status::WT_MODIFIED => adding_file(repo_information.git_repo(), s)?,
-            Status::WT_DELETED => remove_file(repo_information.git_repo(), s)?,
-            _ => panic!("unhandled git state: {:?}", s.status()),
+    // The connection can still drop after the probe, e.g. when the machine
+    // is suspended, which is handled like being offline.
+    let mut online = repo_information.is_online();
+    if online {
+        if let Err(e) = fetch_and_merge(repo_information) {
+            match &e {
+                GitSyncError::Git2(error) if is_transient(error) => {
+                    warn!(
+                        "Lost the connection to {}, skipping fetch: {}",
+                        repo_information.remote(),
+                        error.message()
+                    );
+                    online = false;
+                }
+                _ => return Err(e),
+            }
         }
     }
+    if online {
+        for branch in &repo_information.options().other_branches {
+            if let Err(e) = repo_information.sync_branch(branch) {
+                warn!("Failed to sync {}: {}", branch, e.message());
+            }
+        }
+    } else {
+        info!(
+            "Remote {} is unreachable, skipping fetch",
+            repo_information.remote()
+        );
+    }
+
+    let previous_head = repo_information
+        .git_repo()
+        .head()
+        .ok()
+        .and_then(|head| head.target());
+    if !clean && !held {
+        let commits = commit_changes(repo_information, excludes, rename)?;
+        repo_information.record_commits(commits);
+    }
 
-    repo_information.commit(msg.as_str())?;
-    repo_information.push()?;
+    if !needs_push {
+        return Ok(());
+    }
+    if online {
+        if let Err(e) = push_with_retries(repo_information) {
+            if let GitSyncError::Git2(error) = &e {
+                if is_transient(error) {
+                    warn!(
+                        "Lost the connection to {}, deferring push of {} commit(s): {}",
+                        repo_information.remote(),
+                        repo_information.unpushed_commits()?,
+                        error.message()
+                    );
+                    return Ok(());
+                }
+            }
+            // A rejection that is left after the retries merged remote
+            // commits can't be undone by resetting to the previous head.
+            let permanent = match &e {
+                GitSyncError::Git2(e) => {
+                    !is_transient(e) && e.code() != git2::ErrorCode::NotFastForward
+                }
+                _ => false,
+            };
+            if repo_information.options().rollback_on_push_failure && !clean && !held && permanent {
+                if let Some(previous_head) = previous_head {
+                    warn!(
+                        "Push failed permanently, rolling back the sync commit: {}",
+                        e
+                    );
+                    repo_information.reset_mixed(previous_head)?;
+                }
+            }
+            return Err(e);
+        }
+        // The commits are synced, a missing checkpoint doesn't fail that.
+        if let Err(e) = repo_information.tag_sync() {
+            warn!("Failed to tag the synced commit: {}", e.message());
+        }
+    } else {
+        info!(
+            "offline, deferring push of {} commit(s)",
+            repo_information.unpushed_commits()?
+        );
+    }
     Ok(())
 }
 
-fn adding_file(repo: &Repository, s: StatusEntry) -> Result<String, GitSyncError> {
-    let path = s.path().ok_or(std::io::Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Path is not valid UTF-8",
-    ))?;
-    let new_file = Path::new(path);
-    let mut index = repo.index()?;
-    let msg = format!("Add changes from {} to the repository", new_file.display());
-    info!("{}", msg);
+/// Pushes, and whenever the remote rejects the push because it has new
+/// commits, merges them and tries again, up to `push_retries` times.
+///
+/// Only rejections are retried. An unreachable remote fails right away, its
+/// push is deferred to the next sync instead.
+fn push_with_retries(repo_information: &mut RepoInformation) -> Result<(), GitSyncError> {
+    let mut retries = repo_information.options().push_retries;
+    loop {
+        match repo_information.push() {
+            Err(e) if e.code() == git2::ErrorCode::NotFastForward && retries > 0 => {
+                retries -= 1;
+                warn!("{}, merging the remote commits and retrying", e.message());
+                std::thread::sleep(repo_information.options().push_retry_delay);
+                fetch_and_merge(repo_information)?;
+            }
+            result => return Ok(result?),
+        }
+    }
+}
+
+/// Fetches and merges the remote branch. With `stash_before_merge`, the local
+/// changes are stashed around it and restored even if it fails.
+fn fetch_and_merge(repo_information: &mut RepoInformation) -> Result<(), GitSyncError> {
+    let stashed = repo_information.options().stash_before_merge && repo_information.stash()?;
+    let merged = repo_information.fetch().and_then(|commit| match commit {
+        Some(commit) => repo_information.merge(commit),
+        None => {
+            info!(
+                "{}/{} doesn't exist yet, nothing to merge",
+                repo_information.remote(),
+                repo_information.remote_branch()
+            );
+            Ok(())
+        }
+    });
+    if stashed {
+        if let Err(e) = repo_information.stash_pop() {
+            if let Err(merge_error) = &merged {
+                warn!("Failed to merge: {}", merge_error.message());
+            }
+            return Err(conflict_error(e));
+        }
+    }
+    merged.map_err(conflict_error)
+}
 
-    index.add_path(new_file)?;
-    index.write()?;
-    Ok(msg)
+/// Reports conflicts, e.g. of a merge, as `GitSyncError::Conflict`.
+fn conflict_error(e: git2::Error) -> GitSyncError {
+    match e.code() {
+        git2::ErrorCode::Conflict => GitSyncError::Conflict(e.message().to_owned()),
+        _ => GitSyncError::Git2(e),
+    }
 }
 
-fn remove_file(repo: &Repository, s: StatusEntry) -> Result<String, GitSyncError> {
-    let path = s.path().ok_or(std::io::Error::new(
-        std::io::ErrorKind::InvalidData,
-        "Path is not valid UTF-8",
-    ))?;
-    let new_file = Path::new(path);
-    let mut index = repo.index()?;
-    let msg = format!("Remove {} from the repository", new_file.display());
-    info!("{}", msg);
-
-    index.remove_path(Path::new(path))?;
-    index.write()?;
-    Ok(msg)
+/// Commits the changes in the working tree and returns the number of
+/// commits made.
+fn commit_changes(
+    repo_information: &RepoInformation,
+    excludes: &Excludes,
+    rename: Option<(PathBuf, PathBuf)>,
+) -> Result<usize, GitSyncError> {
+    let mut changes: Vec<Change> = case_renames(repo_information.git_repo())?
+        .into_iter()
+        .map(|(old_file, new_file)| {
+            let change = Change::Renamed(old_file, new_file);
+            info!("{}", change.message());
+            change
+        })
+        .collect();
+    if let Some((from, to)) = rename {
+        if let Some(change) = hinted_rename(repo_information, excludes, from, to)? {
+            changes.push(change);
+        }
+    }
+    // The status lists the paths of these renames again, e.g. as a deleted
+    // and a new file.
+    let renamed: HashSet<PathBuf> = changes
+        .iter()
+        .flat_map(|change| match change {
+            Change::Renamed(old_file, new_file) => vec![old_file.clone(), new_file.clone()],
+            change => vec![change.path().to_path_buf()],
+        })
+        .collect();
+
+    for file in repo_information.backend().status()? {
+        if is_excluded(&file, excludes) {
+            debug!("Not committing {}, it's excluded", file.path.display());
+            continue;
+        }
+        if renamed.contains(&file.path)
+            || file
+                .old_path
+                .as_ref()
+                .map_or(false, |path| renamed.contains(path))
+        {
+            continue;
+        }
+        let change = match file.status {
+            status if status.is_conflicted() => {
+                return Err(GitSyncError::Conflict(format!(
+                    "{} has unresolved conflicts",
+                    file.path.display()
+                )))
+            }
+            // A file can also be staged already, e.g. after `git add`, the
+            // working tree has its latest content.
+            status if status.contains(Status::WT_RENAMED) => {
+                let old_file = file.old_path.ok_or(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Rename without a diff delta",
+                ))?;
+                Change::Renamed(old_file, file.path)
+            }
+            status if status.contains(Status::WT_NEW) => Change::Added(file.path),
+            // E.g. a file replaced by a symlink, staging it stages the new
+            // type.
+            status if status.intersects(Status::WT_MODIFIED | Status::WT_TYPECHANGE) => {
+                Change::Modified(file.path)
+            }
+            status if status.contains(Status::WT_DELETED) => Change::Deleted(file.path),
+            // Already staged, e.g. by a restored stash
+            status if is_staged_only(status) => continue,
+            status => {
+                return Err(GitSyncError::UnsupportedStatus(
+                    file.path.display().to_string(),
+                    format!("{:?}", status),
+                ))
+            }
+        };
+        info!("{}", change.message());
+        changes.push(change);
+    }
+    if changes.is_empty() {
+        debug!("Nothing to commit");
+        return Ok(0);
+    }
+
+    if repo_information.options().commit_per_dir {
+        let mut groups: BTreeMap<Option<PathBuf>, Vec<Change>> = BTreeMap::new();
+        for change in changes {
+            groups
+                .entry(top_level_dir(change.path()))
+                .or_default()
+                .push(change);
+        }
+        for (dir, changes) in &groups {
+            commit(repo_information, changes, dir.as_deref())?;
+        }
+        Ok(groups.len())
+    } else {
+        commit(repo_information, &changes, None)?;
+        Ok(1)
+    }
+}
+
+/// Stages and commits `changes`, which are all inside of `dir` if given.
+fn commit(
+    repo_information: &RepoInformation,
+    changes: &[Change],
+    dir: Option<&Path>,
+) -> Result<(), GitSyncError> {
+    stage(repo_information, changes)?;
+
+    let options = repo_information.options();
+    let msg = match (&options.message, &options.conventional_type) {
+        (Some(message), _) => message.clone(),
+        (None, Some(kind)) => {
+            conventional_message(kind, options.conventional_scope.as_deref(), changes, dir)
+        }
+        (None, None) => commit_message(changes, dir),
+    };
+    let msg = match (&options.message, &options.message_prefix) {
+        (None, Some(prefix)) => format!("{} {}", prefix, msg),
+        _ => msg,
+    };
+    let oid = repo_information.commit(msg.as_str())?;
+
+    let summary = format!(
+        "Committed {}: {}",
+        repo_information.short_id(oid)?,
+        msg.lines().next().unwrap_or_default()
+    );
+    info!("{}", summary);
+    if repo_information.options().print_commits {
+        println!("{}", summary);
+    }
+    Ok(())
+}
+
+/// The first component of `path` if it's inside of a directory, `None` for
+/// files in the root of the working tree.
+fn top_level_dir(path: &Path) -> Option<PathBuf> {
+    let mut components = path.components();
+    let first = components.next()?;
+    components.next()?;
+    Some(PathBuf::from(first.as_os_str()))
+}
+
+/// Stages `changes` for the next sync commit.
+fn stage(repo_information: &RepoInformation, changes: &[Change]) -> Result<(), GitSyncError> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+
+    let mut add = Vec::new();
+    let mut remove = Vec::new();
+    for change in changes {
+        match change {
+            Change::Added(path) | Change::Modified(path) => add.push(path.as_path()),
+            Change::Deleted(path) => remove.push(path.as_path()),
+            Change::Renamed(old_file, new_file) => {
+                remove.push(old_file.as_path());
+                add.push(new_file.as_path());
+            }
+        }
+    }
+    Ok(repo_information.backend().stage(&add, &remove)?)
+}
+
+/// Prints the diff of the changes a sync would commit against HEAD, cut off
+/// after `diff_preview_lines` lines.
+fn print_diff_preview(
+    repo_information: &RepoInformation,
+    excludes: &Excludes,
+) -> Result<(), GitSyncError> {
+    let repo = repo_information.git_repo();
+    let mut diff_options = git2::DiffOptions::new();
+    diff_options
+        .include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    let head = match repo.head() {
+        Ok(head) => Some(head.peel_to_tree()?),
+        Err(_) => None,
+    };
+    let mut diff = repo.diff_tree_to_workdir_with_index(head.as_ref(), Some(&mut diff_options))?;
+    diff.find_similar(Some(git2::DiffFindOptions::new().renames(true)))?;
+
+    let max_lines = repo_information.options().diff_preview_lines;
+    let mut lines = 0;
+    let mut preview = String::new();
+    diff.print(git2::DiffFormat::Patch, |delta, _, line| {
+        let excluded = [delta.old_file().path(), delta.new_file().path()]
+            .iter()
+            .flatten()
+            .any(|path| excludes.is_excluded(path));
+        if excluded {
+            return true;
+        }
+        lines += 1;
+        if lines <= max_lines {
+            if let '+' | '-' | ' ' = line.origin() {
+                preview.push(line.origin());
+            }
+            preview.push_str(&String::from_utf8_lossy(line.content()));
+        }
+        true
+    })?;
+    print!("{}", preview);
+    if lines > max_lines {
+        println!("... {} more lines", lines - max_lines);
+    }
+    Ok(())
+}
+
+/// The number of changes a sync would commit.
+fn pending_changes(
+    repo_information: &RepoInformation,
+    excludes: &Excludes,
+) -> Result<usize, GitSyncError> {
+    Ok(repo_information
+        .backend()
+        .status()?
+        .iter()
+        .filter(|file| !is_excluded(file, excludes))
+        .count())
+}
+
+/// Whether `file`, or the file it was renamed from, is excluded.
+fn is_excluded(file: &FileStatus, excludes: &Excludes) -> bool {
+    excludes.is_excluded(&file.path)
+        || file
+            .old_path
+            .as_deref()
+            .map_or(false, |path| excludes.is_excluded(path))
+}
+
+/// The root of the working tree.
+fn workdir(repo_information: &RepoInformation) -> PathBuf {
+    repo_information
+        .git_repo()
+        .workdir()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from(repo_information.path()))
+}
+
+/// Whether `event` may have changed the content of the working tree.
+///
+/// Notices only announce a following write or removal. Metadata changes are
+/// ignored, a changed executable bit is committed with the next change.
+fn changes_content(event: &DebouncedEvent) -> bool {
+    matches!(
+        event,
+        DebouncedEvent::Create(_)
+            | DebouncedEvent::Write(_)
+            | DebouncedEvent::Remove(_)
+            | DebouncedEvent::Rename(..)
+    )
+}
+
+/// Whether `event` may have changed the exclusions.
+fn touches_exclude_file(event: &DebouncedEvent) -> bool {
+    match event {
+        DebouncedEvent::Rescan => true,
+        event => event_paths(event)
+            .iter()
+            .any(|path| path.file_name() == Some(EXCLUDE_FILE.as_ref())),
+    }
+}
+
+/// Whether a path of `event` matches `--commit-only`, if it was given.
+fn is_wanted(event: &DebouncedEvent, commit_only: Option<&GlobSet>, workdir: &Path) -> bool {
+    let commit_only = match commit_only {
+        Some(commit_only) => commit_only,
+        None => return true,
+    };
+    event_paths(event)
+        .iter()
+        .filter_map(|path| relative_path(path, workdir))
+        .any(|path| commit_only.is_match(path))
+}
+
+fn event_paths(event: &DebouncedEvent) -> Vec<&Path> {
+    match event {
+        DebouncedEvent::NoticeWrite(path)
+        | DebouncedEvent::NoticeRemove(path)
+        | DebouncedEvent::Create(path)
+        | DebouncedEvent::Write(path)
+        | DebouncedEvent::Chmod(path)
+        | DebouncedEvent::Remove(path) => vec![path],
+        DebouncedEvent::Rename(from, to) => vec![from, to],
+        DebouncedEvent::Error(_, Some(path)) => vec![path],
+        DebouncedEvent::Rescan | DebouncedEvent::Error(_, None) => Vec::new(),
+    }
+}
+
+/// The path of a watcher event relative to the working tree, if it's inside
+/// of it and outside of `.git`.
+fn relative_path(path: &Path, workdir: &Path) -> Option<PathBuf> {
+    // Drops the `.` of paths below a relative watch directory like "./a".
+    let path: PathBuf = path.components().collect();
+    let relative = path.strip_prefix(workdir).ok()?.to_path_buf();
+    if relative.starts_with(".git") {
+        return None;
+    }
+    index_path(relative.to_str()).ok()
+}
+
+/// A change staged for a sync commit.
+enum Change {
+    Added(PathBuf),
+    Modified(PathBuf),
+    Deleted(PathBuf),
+    Renamed(PathBuf, PathBuf),
+}
+
+impl Change {
+    /// The path the change leaves in the working tree, or removes from it.
+    fn path(&self) -> &Path {
+        match self {
+            Change::Added(path) | Change::Modified(path) | Change::Deleted(path) => path,
+            Change::Renamed(_, new) => new,
+        }
+    }
+
+    /// The commit message if this is the only change.
+    fn message(&self) -> String {
+        match self {
+            Change::Added(path) | Change::Modified(path) => {
+                format!("Add changes from {} to the repository", path.display())
+            }
+            Change::Deleted(path) => format!("Remove {} from the repository", path.display()),
+            Change::Renamed(old, new) => format!("Rename {} -> {}", old.display(), new.display()),
+        }
+    }
+
+    /// The line listing this change in the message of a bigger commit.
+    fn bullet(&self) -> String {
+        match self {
+            Change::Added(path) => format!("- added {}", path.display()),
+            Change::Modified(path) => format!("- modified {}", path.display()),
+            Change::Deleted(path) => format!("- deleted {}", path.display()),
+            Change::Renamed(old, new) => {
+                format!("- renamed {} -> {}", old.display(), new.display())
+            }
+        }
+    }
+}
+
+/// Describes a single change in one line, several changes as a summary
+/// followed by a list of all of them. The summary names `dir` if all changes
+/// are inside of it.
+fn commit_message(changes: &[Change], dir: Option<&Path>) -> String {
+    match changes {
+        [change] => change.message(),
+        _ => {
+            let mut msg = match dir {
+                Some(dir) => format!("Sync {} changes in {}\n", changes.len(), dir.display()),
+                None => format!("Sync {} changes\n", changes.len()),
+            };
+            for change in changes {
+                msg.push('\n');
+                msg.push_str(&change.bullet());
+            }
+            msg
+        }
+    }
+}
+
+/// The message of `commit_message` as a Conventional Commit of type `kind`,
+/// its summary as the description and every change listed in the body.
+fn conventional_message(
+    kind: &str,
+    scope: Option<&str>,
+    changes: &[Change],
+    dir: Option<&Path>,
+) -> String {
+    let message = commit_message(changes, dir);
+    let summary = message.lines().next().unwrap_or_default();
+    // Commit linters reject descriptions in sentence case.
+    let mut chars = summary.chars();
+    let description: String = chars
+        .next()
+        .map(|first| first.to_lowercase().chain(chars).collect())
+        .unwrap_or_default();
+
+    let mut msg = match scope.filter(|scope| !scope.is_empty()) {
+        Some(scope) => format!("{}({}): {}\n", kind, scope, description),
+        None => format!("{}: {}\n", kind, description),
+    };
+    for change in changes {
+        msg.push('\n');
+        msg.push_str(&change.bullet());
+    }
+    msg
+}
+
+/// Validates a type or scope of Conventional Commits messages.
+fn conventional_token(token: String) -> Result<(), String> {
+    if !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "\"{}\" may only contain letters, digits, - and _",
+            token
+        ))
+    }
+}
+
+fn is_staged_only(status: Status) -> bool {
+    let worktree = Status::WT_NEW
+        | Status::WT_MODIFIED
+        | Status::WT_DELETED
+        | Status::WT_RENAMED
+        | Status::WT_TYPECHANGE;
+    !status.intersects(worktree) && !status.is_conflicted() && !status.is_empty()
+}
+
+/// The rename of a tracked file from `from` to `to`, both relative to the
+/// working tree, as reported by the watcher.
+///
+/// Returns `None` if it's not such a rename, e.g. a directory or a file that
+/// isn't tracked yet, and leaves it to the status scan.
+fn hinted_rename(
+    repo_information: &RepoInformation,
+    excludes: &Excludes,
+    from: PathBuf,
+    to: PathBuf,
+) -> Result<Option<Change>, GitSyncError> {
+    let index = repo_information.git_repo().index()?;
+    if index.get_path(&from, 0).is_none()
+        || !workdir(repo_information).join(&to).is_file()
+        || excludes.is_excluded(&from)
+        || excludes.is_excluded(&to)
+    {
+        return Ok(None);
+    }
+
+    let change = Change::Renamed(from, to);
+    info!("{}", change.message());
+    Ok(Some(change))
+}
+
+/// The paths of a rename event relative to the working tree, if both are
+/// inside of it and outside of `.git`.
+fn renamed_paths(event: &DebouncedEvent, workdir: &Path) -> Option<(PathBuf, PathBuf)> {
+    match event {
+        DebouncedEvent::Rename(from, to) => {
+            Some((relative_path(from, workdir)?, relative_path(to, workdir)?))
+        }
+        _ => None,
+    }
+}
+
+/// Finds index entries whose name on disk differs by case only.
+///
+/// With `core.ignorecase` set, e.g. on the default filesystems of macOS and
+/// Windows, git treats `Readme.md` and `README.md` as the same file, so such
+/// a rename doesn't show up in the status and the index keeps the old name.
+/// Every index entry is compared against the actual name on disk instead.
+fn case_renames(repo: &Repository) -> Result<Vec<(PathBuf, PathBuf)>, GitSyncError> {
+    let ignorecase = repo.config()?.get_bool("core.ignorecase").unwrap_or(false);
+    let workdir = match repo.workdir() {
+        Some(workdir) if ignorecase => workdir,
+        _ => return Ok(Vec::new()),
+    };
+
+    let mut listings: HashMap<PathBuf, Vec<OsString>> = HashMap::new();
+    let mut renames = Vec::new();
+    for entry in repo.index()?.iter() {
+        let path = PathBuf::from(String::from_utf8_lossy(&entry.path).into_owned());
+        if let Some(actual) = actual_case(workdir, &path, &mut listings)? {
+            if actual != path {
+                renames.push((path, actual));
+            }
+        }
+    }
+    Ok(renames)
+}
+
+/// Returns `path` with every component spelled as it is on disk, or `None`
+/// if it doesn't exist.
+fn actual_case(
+    workdir: &Path,
+    path: &Path,
+    listings: &mut HashMap<PathBuf, Vec<OsString>>,
+) -> Result<Option<PathBuf>, GitSyncError> {
+    let mut actual = PathBuf::new();
+    for component in path.iter() {
+        let dir = workdir.join(&actual);
+        if !listings.contains_key(&dir) {
+            let names = match std::fs::read_dir(&dir) {
+                Ok(entries) => entries
+                    .map(|entry| entry.map(|entry| entry.file_name()))
+                    .collect::<Result<_, _>>()?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+            listings.insert(dir.clone(), names);
+        }
+
+        let names = &listings[&dir];
+        let wanted = component.to_string_lossy().to_lowercase();
+        let name = names
+            .iter()
+            .find(|name| name.as_os_str() == component)
+            .or_else(|| {
+                names
+                    .iter()
+                    .find(|name| name.to_string_lossy().to_lowercase() == wanted)
+            });
+        match name {
+            Some(name) => actual.push(name),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(actual))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testutil::TestRepo;
+
+    #[test]
+    fn update_commits_and_pushes_new_files() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        test_repo.write("notes/todo.md", "sync me\n");
+
+        let excludes = Excludes::load(&workdir(&repo_information));
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let remote = test_repo.remote();
+        let head = remote.find_commit(test_repo.remote_head()).unwrap();
+        assert_eq!(
+            head.summary(),
+            Some("Add changes from notes/todo.md to the repository")
+        );
+        assert!(head
+            .tree()
+            .unwrap()
+            .get_path(Path::new("notes/todo.md"))
+            .is_ok());
+        assert_eq!(repo_information.unpushed_commits().unwrap(), 0);
+    }
+
+    #[test]
+    fn conflicting_merges_fail_and_conflicts_are_not_committed() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        test_repo.commit_on_remote("README", "remote\n");
+        test_repo.write("README", "local\n");
+        stage(
+            &repo_information,
+            &[Change::Modified(PathBuf::from("README"))],
+        )
+        .unwrap();
+        repo_information.commit("Change README locally").unwrap();
+
+        let result = update(&mut repo_information, &excludes, None, false);
+        assert!(matches!(result, Err(GitSyncError::Conflict(_))));
+
+        let result = commit_changes(&repo_information, &excludes, None);
+        assert!(matches!(result, Err(GitSyncError::Conflict(_))));
+        assert_eq!(repo_information.unpushed_commits().unwrap(), 1);
+    }
+
+    #[test]
+    fn stashed_changes_are_restored_when_the_merge_fails() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let mut options = repo_information.options().clone();
+        options.stash_before_merge = true;
+        options.merge_mode = MergeMode::FastForwardOnly;
+        repo_information.set_options(options);
+        test_repo.commit_on_remote("other", "remote\n");
+        test_repo.write("README", "local\n");
+        stage(
+            &repo_information,
+            &[Change::Modified(PathBuf::from("README"))],
+        )
+        .unwrap();
+        repo_information.commit("Change README locally").unwrap();
+        test_repo.write("notes", "unsynced\n");
+
+        assert!(fetch_and_merge(&mut repo_information).is_err());
+        assert_eq!(test_repo.read("notes"), "unsynced\n");
+        assert!(repo_information
+            .git_repo()
+            .find_reference("refs/stash")
+            .is_err());
+    }
+
+    #[test]
+    fn fast_forwards_keep_uncommitted_changes() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        test_repo.commit_on_remote("other", "remote\n");
+        test_repo.write("README", "local\n");
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        assert_eq!(test_repo.read("README"), "local\n");
+        assert_eq!(test_repo.read("other"), "remote\n");
+        let remote = test_repo.remote();
+        let tree = remote
+            .find_commit(test_repo.remote_head())
+            .unwrap()
+            .tree()
+            .unwrap();
+        let readme = tree.get_path(Path::new("README")).unwrap();
+        let blob = remote.find_blob(readme.id()).unwrap();
+        assert_eq!(blob.content(), b"local\n");
+    }
+
+    #[test]
+    fn update_commits_staged_files_that_changed_again() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        test_repo.write("README", "staged\n");
+        stage(
+            &repo_information,
+            &[Change::Modified(PathBuf::from("README"))],
+        )
+        .unwrap();
+        test_repo.write("README", "changed again\n");
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        assert_eq!(pending_changes(&repo_information, &excludes).unwrap(), 0);
+        assert_eq!(repo_information.unpushed_commits().unwrap(), 0);
+    }
+
+    #[test]
+    fn update_pushes_a_branch_the_remote_doesnt_have_yet() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        test_repo
+            .remote()
+            .find_reference("refs/heads/master")
+            .unwrap()
+            .delete()
+            .unwrap();
+        if let Ok(mut tracking) = repo_information
+            .git_repo()
+            .find_reference("refs/remotes/origin/master")
+        {
+            tracking.delete().unwrap();
+        }
+        test_repo.write("notes.md", "first sync\n");
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let head = repo_information.git_repo().head().unwrap().target();
+        assert_eq!(head, Some(test_repo.remote_head()));
+    }
+
+    #[test]
+    fn deleting_a_directory_removes_all_of_its_files() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        test_repo.write("docs/a.md", "a\n");
+        test_repo.write("docs/guide/b.md", "b\n");
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        std::fs::remove_dir_all(workdir(&repo_information).join("docs")).unwrap();
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let index = repo_information.git_repo().index().unwrap();
+        let paths: Vec<Vec<u8>> = index.iter().map(|entry| entry.path).collect();
+        assert_eq!(paths, [b"README".to_vec()]);
+        assert_eq!(
+            repo_information.git_repo().head().unwrap().target(),
+            Some(test_repo.remote_head())
+        );
+    }
+
+    #[test]
+    fn case_only_renames_replace_the_old_name_in_the_index() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        // Emulates a case-insensitive filesystem, where git sets this. The
+        // rename below is a real one on case-sensitive filesystems.
+        repo_information
+            .git_repo()
+            .config()
+            .unwrap()
+            .set_bool("core.ignorecase", true)
+            .unwrap();
+        let workdir = workdir(&repo_information);
+        std::fs::rename(workdir.join("README"), workdir.join("Readme")).unwrap();
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let index = repo_information.git_repo().index().unwrap();
+        let paths: Vec<Vec<u8>> = index.iter().map(|entry| entry.path).collect();
+        assert_eq!(paths, [b"Readme".to_vec()]);
+        let head = repo_information
+            .git_repo()
+            .head()
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(head.summary(), Some("Rename README -> Readme"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn update_commits_a_file_replaced_by_a_symlink() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        let readme = workdir(&repo_information).join("README");
+        std::fs::remove_file(&readme).unwrap();
+        std::os::unix::fs::symlink("elsewhere", &readme).unwrap();
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let remote = test_repo.remote();
+        let tree = remote
+            .find_commit(test_repo.remote_head())
+            .unwrap()
+            .tree()
+            .unwrap();
+        let entry = tree.get_path(Path::new("README")).unwrap();
+        assert_eq!(entry.filemode(), 0o120000);
+    }
+
+    #[test]
+    fn update_commits_locally_when_the_connection_drops() {
+        // Accepts connections, so the remote looks online, but closes them
+        // right away.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo.git", listener.local_addr().unwrap());
+        std::thread::spawn(move || for _ in listener.incoming() {});
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        repo_information
+            .git_repo()
+            .remote_set_url("origin", &url)
+            .unwrap();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        test_repo.write("notes.md", "offline\n");
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        assert_eq!(pending_changes(&repo_information, &excludes).unwrap(), 0);
+        assert_eq!(repo_information.unpushed_commits().unwrap(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn update_skips_paths_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let excludes = Excludes::load(&workdir(&repo_information));
+        let name = std::ffi::OsStr::from_bytes(b"invalid-\xff");
+        std::fs::write(workdir(&repo_information).join(name), "skipped\n").unwrap();
+        test_repo.write("notes.md", "synced\n");
+
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let remote = test_repo.remote();
+        let tree = remote
+            .find_commit(test_repo.remote_head())
+            .unwrap()
+            .tree()
+            .unwrap();
+        assert!(tree.get_path(Path::new("notes.md")).is_ok());
+        assert_eq!(tree.len(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn events_below_a_symlinked_directory_are_inside_the_working_tree() {
+        let test_repo = TestRepo::new();
+        let repo_information = test_repo.repo_information();
+        let workdir = workdir(&repo_information);
+        let link = workdir.parent().unwrap().join("link");
+        std::os::unix::fs::symlink(&workdir, &link).unwrap();
+
+        let watched = watched_dir(link.to_str().unwrap());
+
+        assert_eq!(
+            relative_path(&watched.join("notes.org"), &workdir),
+            Some(PathBuf::from("notes.org"))
+        );
+    }
+
+    #[test]
+    fn tag_on_sync_pushes_a_tag_with_a_unique_name_per_push() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let mut options = repo_information.options().clone();
+        options.tag_pattern = Some("sync-{timestamp}".to_owned());
+        options.push_tags = true;
+        repo_information.set_options(options);
+        let excludes = Excludes::load(&workdir(&repo_information));
+
+        for content in &["a\n", "b\n"] {
+            test_repo.write("notes.md", content);
+            update(&mut repo_information, &excludes, None, false).unwrap();
+        }
+
+        let remote = test_repo.remote();
+        let mut tags: Vec<String> = remote
+            .tag_names(None)
+            .unwrap()
+            .iter()
+            .flatten()
+            .map(str::to_owned)
+            .collect();
+        tags.sort();
+        assert_eq!(tags, ["sync-1600000000", "sync-1600000000-1"]);
+        let latest = remote
+            .revparse_single("sync-1600000000-1^{commit}")
+            .unwrap();
+        assert_eq!(latest.id(), test_repo.remote_head());
+    }
+
+    #[test]
+    fn max_commits_holds_back_changes_once_the_limit_is_reached() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let mut options = repo_information.options().clone();
+        options.max_commits = Some(1);
+        options.max_commits_per = Some(Duration::from_secs(3600));
+        repo_information.set_options(options);
+        let excludes = Excludes::load(&workdir(&repo_information));
+
+        test_repo.write("a.md", "a\n");
+        update(&mut repo_information, &excludes, None, false).unwrap();
+        let synced = test_repo.remote_head();
+        test_repo.write("b.md", "b\n");
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        assert_eq!(test_repo.remote_head(), synced);
+        assert_eq!(pending_changes(&repo_information, &excludes).unwrap(), 1);
+        assert!(repo_information.commit_window_reopens_in().is_some());
+    }
+
+    #[test]
+    fn held_changes_survive_periodic_fast_forwards() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let mut options = repo_information.options().clone();
+        options.min_changes = 2;
+        repo_information.set_options(options);
+        let excludes = Excludes::load(&workdir(&repo_information));
+        let remote_commit = test_repo.commit_on_remote("other", "remote\n");
+        test_repo.write("README", "local\n");
+
+        update(&mut repo_information, &excludes, None, true).unwrap();
+
+        let head = repo_information.git_repo().head().unwrap().target();
+        assert_eq!(head, Some(remote_commit));
+        assert_eq!(test_repo.read("README"), "local\n");
+        assert_eq!(pending_changes(&repo_information, &excludes).unwrap(), 1);
+    }
+
+    #[test]
+    fn commit_per_dir_commits_every_top_level_directory_on_its_own() {
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        let mut options = repo_information.options().clone();
+        options.commit_per_dir = true;
+        repo_information.set_options(options);
+        test_repo.write("docs/a.md", "a\n");
+        test_repo.write("docs/guide/b.md", "b\n");
+        test_repo.write("src/c.rs", "c\n");
+        test_repo.write("README", "changed\n");
+
+        let excludes = Excludes::load(&workdir(&repo_information));
+        update(&mut repo_information, &excludes, None, false).unwrap();
+
+        let remote = test_repo.remote();
+        let mut revwalk = remote.revwalk().unwrap();
+        revwalk.push(test_repo.remote_head()).unwrap();
+        let mut summaries: Vec<String> = revwalk
+            .map(|oid| {
+                let commit = remote.find_commit(oid.unwrap()).unwrap();
+                commit.summary().unwrap().to_owned()
+            })
+            .collect();
+        summaries.reverse();
+        assert_eq!(
+            summaries,
+            [
+                "init",
+                "Add changes from README to the repository",
+                "Sync 2 changes in docs",
+                "Add changes from src/c.rs to the repository",
+            ]
+        );
+    }
 }
@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::GitSyncError;
+
+/// A file containing the PID of the running git-sync process.
+///
+/// The file is removed again when the value is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the current PID to `path`.
+    ///
+    /// Fails if the file already names a process that is still alive. A
+    /// stale file left behind by a dead process is replaced.
+    pub fn create(path: &Path) -> Result<Self, GitSyncError> {
+        if let Ok(content) = fs::read_to_string(path) {
+            match content.trim().parse::<u32>() {
+                Ok(pid) if is_alive(pid) => {
+                    return Err(GitSyncError::PidFileInUse(path.display().to_string(), pid));
+                }
+                _ => {
+                    info!("Removing stale pid file {}", path.display());
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+
+        fs::write(path, format!("{}\n", std::process::id()))?;
+        debug!("Wrote pid file {}", path.display());
+        Ok(Self {
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Failed to remove pid file {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_alive(pid: u32) -> bool {
+    // Signal 0 performs the permission and existence checks only. EPERM
+    // means the process exists but belongs to someone else.
+    let ret = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+fn is_alive(_pid: u32) -> bool {
+    // Without a way to probe the process, treat the file as stale. Otherwise
+    // a file left behind by a crash would keep git-sync from starting until
+    // it's removed by hand. Two instances on the same repository are still
+    // kept apart by the repository lock.
+    false
+}
@@ -1,38 +1,275 @@
-type GitResult<T> = Result<T, git2::Error>;
-static FETCH_HEAD: &str = "FETCH_HEAD";
+use std::collections::VecDeque;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::backend::Git2Backend;
+use crate::hooks;
+use crate::signature;
+
+pub(crate) type GitResult<T> = Result<T, git2::Error>;
+static ONLINE_PROBE_TIMEOUT: Duration = Duration::from_secs(3);
+static DEFAULT_MERGE_MESSAGE_TEMPLATE: &str = "Merge {remote} into {local}";
+
+/// How `merge` integrates the fetched commit.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum MergeMode {
+    /// Fast-forward when possible, otherwise create a merge commit.
+    #[default]
+    FastForward,
+    /// Only fast-forward, fail if the branches diverged (`--ff-only`).
+    FastForwardOnly,
+    /// Always create a merge commit, even if a fast-forward is possible
+    /// (`--no-ff`).
+    NoFastForward,
+}
+
+impl std::str::FromStr for MergeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ff" => Ok(MergeMode::FastForward),
+            "ff-only" => Ok(MergeMode::FastForwardOnly),
+            "no-ff" => Ok(MergeMode::NoFastForward),
+            _ => Err(format!("unknown merge strategy \"{}\"", s)),
+        }
+    }
+}
+
+/// Which branches `push` pushes where, from `push.default`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PushDefault {
+    /// Push the branch to the synced remote branch. Unlike git, a remote
+    /// branch with another name is not refused, it was asked for explicitly.
+    #[default]
+    Simple,
+    /// Push the branch to the branch of the same name on the remote.
+    Current,
+    /// Push the branch to the synced remote branch, its upstream unless
+    /// configured otherwise.
+    Upstream,
+    /// Push the branch and every other local branch that exists on the
+    /// remote under the same name.
+    Matching,
+    /// Refuse to push.
+    Nothing,
+}
+
+impl std::str::FromStr for PushDefault {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "simple" => Ok(PushDefault::Simple),
+            "current" => Ok(PushDefault::Current),
+            // "tracking" is the deprecated name of "upstream"
+            "upstream" | "tracking" => Ok(PushDefault::Upstream),
+            "matching" => Ok(PushDefault::Matching),
+            "nothing" => Ok(PushDefault::Nothing),
+            _ => Err(format!("unknown push.default \"{}\"", s)),
+        }
+    }
+}
+
+/// Where the author of sync commits comes from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum AuthorSource {
+    /// `user.name` and `user.email` of the git config.
+    #[default]
+    Config,
+    /// `GIT_AUTHOR_NAME` and `GIT_AUTHOR_EMAIL` of the environment.
+    Env,
+    /// The author of the commit at HEAD.
+    LastCommit,
+}
+
+impl std::str::FromStr for AuthorSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "config" => Ok(AuthorSource::Config),
+            "env" => Ok(AuthorSource::Env),
+            "last-commit" => Ok(AuthorSource::LastCommit),
+            _ => Err(format!("unknown author source \"{}\"", s)),
+        }
+    }
+}
+
+/// How git-sync authenticates against the remote.
+#[derive(Debug, Default, Clone)]
+pub enum Credentials {
+    /// Ask the SSH agent for a key.
+    #[default]
+    SshAgent,
+    /// Use a private key file.
+    SshKey(PathBuf),
+    /// Send a token as password over HTTPS.
+    Token(String),
+}
+
+impl Credentials {
+    /// The callbacks used for every connection to the remote, so fetch and
+    /// push authenticate the same way.
+    pub fn callbacks(&self) -> git2::RemoteCallbacks<'_> {
+        let mut callbacks = git2::RemoteCallbacks::new();
+        callbacks.credentials(move |_url, username_from_url, allowed_types| {
+            self.get(username_from_url, allowed_types)
+        });
+        callbacks
+    }
+
+    fn get(
+        &self,
+        username_from_url: Option<&str>,
+        allowed_types: git2::CredentialType,
+    ) -> GitResult<git2::Cred> {
+        let username = username_from_url.unwrap_or("git");
+        match self {
+            Credentials::Token(token)
+                if allowed_types.contains(git2::CredentialType::USER_PASS_PLAINTEXT) =>
+            {
+                info!("Authenticate with token");
+                git2::Cred::userpass_plaintext(username, token)
+            }
+            Credentials::SshKey(key) => {
+                info!("Authenticate with SSH key {}", key.display());
+                git2::Cred::ssh_key(username, None, key, None)
+            }
+            _ => {
+                info!("Ask agent for SSH key");
+                git2::Cred::ssh_key_from_agent(username)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct SyncOptions {
+    /// Stash the working changes before fetching and merging, and restore
+    /// them afterwards, so the merge runs against a clean tree.
+    pub stash_before_merge: bool,
+    pub merge_mode: MergeMode,
+    /// The committer of sync commits, if it should be different from the
+    /// author. Missing parts are taken from the author.
+    pub committer_name: Option<String>,
+    pub committer_email: Option<String>,
+    /// A fixed UTC offset in minutes for commit timestamps. The local
+    /// timezone of the machine is used when unset.
+    pub utc_offset: Option<i32>,
+    /// A fixed unix time for the timestamps of sync and merge commits, so the
+    /// same changes always produce the same commit ids, e.g. in tests. The
+    /// current time is used when unset.
+    pub commit_time: Option<i64>,
+    pub credentials: Credentials,
+    /// The message of merge commits. `{remote}` and `{local}` are replaced by
+    /// the abbreviated ids of the merged commits, `{branch}` by the branch and
+    /// `{timestamp}` by the current unix time.
+    pub merge_message_template: Option<String>,
+    /// Prepended to the message of every sync commit, e.g. `[auto]`.
+    pub message_prefix: Option<String>,
+    /// The message of every sync commit, used verbatim instead of the
+    /// generated one.
+    pub message: Option<String>,
+    /// The type and scope of Conventional Commits style sync messages, e.g.
+    /// `chore(sync): sync 2 changes`. The messages are free-form without a
+    /// type, an empty scope is left out.
+    pub conventional_type: Option<String>,
+    pub conventional_scope: Option<String>,
+    /// Print the id and summary of every sync commit to stdout.
+    pub print_commits: bool,
+    /// Make one sync commit per top-level directory instead of one for all
+    /// changes. The files in the root of the working tree get a commit of
+    /// their own as well.
+    pub commit_per_dir: bool,
+    /// Drop the sync commit again if the push fails for a reason retrying
+    /// won't fix, e.g. the remote rejecting it.
+    pub rollback_on_push_failure: bool,
+    /// Refuse to merge fetched commits without a good GPG signature.
+    pub verify_signatures: bool,
+    /// The keyring used to verify signatures instead of the default one.
+    pub keyring: Option<PathBuf>,
+    /// The branch on the remote to sync with, if it is named differently
+    /// than the local branch.
+    pub remote_branch: Option<String>,
+    /// Whether to point the upstream of the branch at the synced remote
+    /// branch after a push. `None` only sets it if none is configured yet,
+    /// `Some(true)` replaces an existing one and `Some(false)` never touches
+    /// it.
+    pub set_upstream: Option<bool>,
+    /// Force push every local ref and delete the remote refs that don't
+    /// exist locally, like `git push --mirror`. Nothing is committed or
+    /// merged in this mode.
+    pub mirror: bool,
+    /// Where the author of sync commits comes from. The git config is used
+    /// if the source doesn't provide one.
+    pub author_from: AuthorSource,
+    /// Don't sign sync commits even if `commit.gpgsign` is set.
+    pub no_sign: bool,
+    /// Run the git hooks around sync commits and merges, libgit2 skips them.
+    pub run_hooks: bool,
+    /// Skip the pre-commit hook even if hooks are run, e.g. because it's
+    /// broken or rejects changes that should be synced anyway.
+    pub no_verify: bool,
+    /// How often a push rejected because the remote has new commits is
+    /// retried after merging them, and how long to wait before each retry.
+    pub push_retries: u32,
+    pub push_retry_delay: Duration,
+    /// The url to create the remote with if it doesn't exist.
+    pub remote_url: Option<String>,
+    /// The name of the tag `tag_sync` creates after a push, see there.
+    pub tag_pattern: Option<String>,
+    /// The minimum time between two tags.
+    pub tag_interval: Option<Duration>,
+    /// Push the tags `tag_sync` creates.
+    pub push_tags: bool,
+    /// Changes are only committed once at least this many files changed.
+    pub min_changes: usize,
+    /// At most this many sync commits are made within `max_commits_per`,
+    /// changes made after that are committed together once older commits
+    /// leave the window.
+    pub max_commits: Option<usize>,
+    pub max_commits_per: Option<Duration>,
+    /// The refspecs to fetch instead of the remote branch into its
+    /// remote-tracking branch. They have to include the remote branch, the
+    /// commit fetched for it is merged.
+    pub fetch_refspecs: Vec<String>,
+    /// Only preview the changes a sync would commit, as a diff of at most
+    /// `diff_preview_lines` lines, without committing, fetching or pushing.
+    pub dry_run: bool,
+    pub diff_preview_lines: usize,
+    /// More branches to sync besides the checked out one, see
+    /// `RepoInformation::sync_branch`.
+    pub other_branches: Vec<String>,
+}
 
 pub struct RepoInformation<'a> {
     path: &'a str,
     remote: &'a str,
     branch: &'a str,
-    git_repo: git2::Repository,
+    backend: Git2Backend,
+    options: SyncOptions,
+    /// When `tag_sync` created the last tag.
+    last_tag: Option<Instant>,
+    /// When the sync commits within the last `max_commits_per` were made,
+    /// the oldest first.
+    recent_commits: VecDeque<Instant>,
 }
 
 impl<'a> RepoInformation<'a> {
-    pub fn init(path: &'a str, remote: &'a str, branch: &'a str) -> Self {
-        let git_repo = git2::Repository::init(path).unwrap();
-        Self {
-            path,
-            remote,
-            branch,
-            git_repo,
-        }
-    }
-
-    pub fn new(path: &'a str, remote: &'a str, branch: &'a str) -> Self {
-        let git_repo = git2::Repository::open(path).unwrap();
+    fn with_backend(path: &'a str, remote: &'a str, branch: &'a str, backend: Git2Backend) -> Self {
         Self {
             path,
             remote,
             branch,
-            git_repo,
+            backend,
+            options: SyncOptions::default(),
+            last_tag: None,
+            recent_commits: VecDeque::new(),
         }
     }
 
-    pub fn is_repo(path: &str) -> bool {
-        git2::Repository::open(path).is_ok()
-    }
-
     pub fn path(&self) -> &'a str {
         self.path
     }
@@ -45,79 +282,364 @@ impl<'a> RepoInformation<'a> {
         self.branch
     }
 
-    pub fn git_repo(&self) -> &git2::Repository {
-        &self.git_repo
+    /// The branch on the remote, the local branch name unless configured
+    /// otherwise.
+    pub fn remote_branch(&self) -> &str {
+        self.options.remote_branch.as_deref().unwrap_or(self.branch)
     }
 
-    pub fn commit(&self, commit_msg: &str) -> GitResult<()> {
-        let config = self.git_repo.config()?.snapshot()?;
-        let author = config.get_str("user.name")?;
-        let email = config.get_str("user.email")?;
+    pub fn backend(&self) -> &Git2Backend {
+        &self.backend
+    }
 
-        let update_ref = "HEAD";
-        let signature = git2::Signature::now(author, email)?;
-        let mut index = self.git_repo.index()?;
-        let tree_oid = index.write_tree()?;
-        let tree = self.git_repo.find_tree(tree_oid)?;
+    pub fn options(&self) -> &SyncOptions {
+        &self.options
+    }
 
-        info!("New commit: {}, {}, {}", update_ref, &signature, commit_msg);
+    pub fn set_options(&mut self, options: SyncOptions) {
+        self.options = options;
+    }
 
-        let commits = match self.git_repo.head() {
-            // TODO: Replace unwrap
-            Ok(r) => {
-                let oid = r.target().unwrap();
-                vec![self.git_repo.find_commit(oid)?]
+    /// Whether `max_commits` sync commits were made within the last
+    /// `max_commits_per`, so changes have to wait.
+    pub fn commit_limit_reached(&mut self) -> bool {
+        let (max_commits, window) = match (self.options.max_commits, self.options.max_commits_per) {
+            (Some(max_commits), Some(window)) => (max_commits, window),
+            _ => return false,
+        };
+        while let Some(oldest) = self.recent_commits.front() {
+            if oldest.elapsed() < window {
+                break;
             }
+            self.recent_commits.pop_front();
+        }
+        self.recent_commits.len() >= max_commits
+    }
+
+    /// How long until the oldest commit leaves the window, if the commit
+    /// limit is reached.
+    pub fn commit_window_reopens_in(&mut self) -> Option<Duration> {
+        if !self.commit_limit_reached() {
+            return None;
+        }
+        let window = self.options.max_commits_per?;
+        let oldest = self.recent_commits.front()?;
+        Some(window.saturating_sub(oldest.elapsed()))
+    }
+
+    /// Counts `count` sync commits made just now against the commit limit.
+    pub fn record_commits(&mut self, count: usize) {
+        if self.options.max_commits.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        self.recent_commits
+            .extend(std::iter::repeat(now).take(count));
+    }
 
-            Err(_) => {
-                // HEAD does not Exist; Return a vector without any commits
-                Vec::new()
+    pub fn init(path: &'a str, remote: &'a str, branch: &'a str) -> Self {
+        Self::with_backend(path, remote, branch, Git2Backend::init(path).unwrap())
+    }
+
+    pub fn new(path: &'a str, remote: &'a str, branch: &'a str) -> Self {
+        let backend = Git2Backend::open(path).unwrap();
+
+        // Fast-forwarding and pushing operate on the configured branch, so
+        // they would miss the working tree if another branch is checked out.
+        if let Some(current) = current_branch(backend.repository()) {
+            if !branch.is_empty() && branch != current {
+                warn!(
+                    "Syncing branch {} while {} is checked out in {}",
+                    branch, current, path
+                );
+            }
+        }
+
+        Self::with_backend(path, remote, branch, backend)
+    }
+
+    pub fn is_repo(path: &str) -> bool {
+        git2::Repository::open(path).is_ok()
+    }
+
+    /// Opens the repository again, e.g. after it was deleted and restored.
+    pub fn reopen(&mut self) -> GitResult<()> {
+        self.backend = Git2Backend::open(self.path)?;
+        Ok(())
+    }
+
+    pub fn git_repo(&self) -> &git2::Repository {
+        self.backend.repository()
+    }
+
+    /// The number of commits on the local branch the remote-tracking branch
+    /// doesn't have yet, e.g. because they were committed while offline.
+    pub fn unpushed_commits(&self) -> GitResult<usize> {
+        let local = match self
+            .git_repo()
+            .refname_to_id(&format!("refs/heads/{}", self.branch()))
+        {
+            Ok(oid) => oid,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        match self.git_repo().refname_to_id(&format!(
+            "refs/remotes/{}/{}",
+            self.remote(),
+            self.remote_branch()
+        )) {
+            Ok(remote) => Ok(self.git_repo().graph_ahead_behind(local, remote)?.0),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                // Nothing was pushed so far
+                let mut revwalk = self.git_repo().revwalk()?;
+                revwalk.push(local)?;
+                Ok(revwalk.count())
             }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Checks whether the host of the remote accepts connections.
+    ///
+    /// Local remotes and remotes whose address can't be determined are
+    /// considered online, fetching from them reports the actual problem.
+    pub fn is_online(&self) -> bool {
+        let remote = match self.git_repo().find_remote(self.remote()) {
+            Ok(remote) => remote,
+            Err(_) => return true,
+        };
+        let (host, port) = match remote.url().and_then(remote_address) {
+            Some(address) => address,
+            None => return true,
         };
 
-        self.git_repo.commit(
-            Some(update_ref),
-            &signature,
-            &signature,
-            &commit_msg,
-            &tree,
-            &commits.iter().collect::<Vec<_>>(),
+        let online = match (host.as_str(), port).to_socket_addrs() {
+            Ok(mut addrs) => {
+                addrs.any(|addr| TcpStream::connect_timeout(&addr, ONLINE_PROBE_TIMEOUT).is_ok())
+            }
+            Err(_) => false,
+        };
+        debug!(
+            "{}:{} is {}",
+            host,
+            port,
+            if online { "up" } else { "down" }
+        );
+        online
+    }
+
+    /// Connects to the remote to check that it's reachable and accepts the
+    /// credentials.
+    pub fn check_connection(&self) -> GitResult<()> {
+        let mut remote = self.git_repo().find_remote(self.remote())?;
+        remote.connect_auth(
+            git2::Direction::Fetch,
+            Some(self.options.credentials.callbacks()),
+            None,
         )?;
-        Ok(())
+        remote.disconnect()
     }
 
-    pub fn fetch(&self) -> GitResult<git2::AnnotatedCommit> {
-        let mut remote = self.git_repo.find_remote(self.remote()).unwrap();
+    pub fn commit(&self, commit_msg: &str) -> GitResult<git2::Oid> {
+        let (author, email) = self.author()?;
 
-        let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            info!("Ask agent for SSH key");
-            git2::Cred::ssh_key_from_agent(username_from_url.unwrap())
-        });
+        let signature = self.signature(&author, &email)?;
+        let committer = self.signature(
+            self.options.committer_name.as_deref().unwrap_or(&author),
+            self.options.committer_email.as_deref().unwrap_or(&email),
+        )?;
+        if self.options.run_hooks && !self.options.no_verify {
+            hooks::run(self.git_repo(), "pre-commit", &[])?;
+        }
+        let signing_key = self.signing_key()?;
+        let oid =
+            self.backend
+                .commit(&signature, &committer, commit_msg, signing_key.as_deref())?;
+        self.run_post_hook("post-commit", &[]);
+        Ok(oid)
+    }
 
-        let mut fetch_options = git2::FetchOptions::new();
-        fetch_options.remote_callbacks(callbacks);
-        //fetch_options.download_tags(git2::AutotagOption::All);
-        info!(
-            "Fetching {}/{} for repo",
-            remote.name().unwrap(),
-            self.branch()
+    /// Runs a hook that only notifies about a finished commit or merge, so
+    /// its failure doesn't fail the sync.
+    fn run_post_hook(&self, name: &str, args: &[&str]) {
+        if !self.options.run_hooks {
+            return;
+        }
+        if let Err(e) = hooks::run(self.git_repo(), name, args) {
+            warn!("{}", e.message());
+        }
+    }
+
+    /// The key to sign sync commits with, if `commit.gpgsign` asks for
+    /// signed commits and `user.signingkey` names a key.
+    fn signing_key(&self) -> GitResult<Option<String>> {
+        if self.options.no_sign {
+            return Ok(None);
+        }
+        let config = self.git_repo().config()?.snapshot()?;
+        if !config.get_bool("commit.gpgsign").unwrap_or(false) {
+            return Ok(None);
+        }
+        match config.get_string("user.signingkey") {
+            Ok(key) => Ok(Some(key)),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                warn!("commit.gpgsign is set, but user.signingkey is not, not signing");
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The abbreviated, but still unique, id of an object.
+    pub fn short_id(&self, oid: git2::Oid) -> GitResult<String> {
+        let buf = self.git_repo().find_object(oid, None)?.short_id()?;
+        Ok(buf.as_str().unwrap_or_default().to_owned())
+    }
+
+    /// The name and email of the author of sync commits.
+    fn author(&self) -> GitResult<(String, String)> {
+        match self.options.author_from {
+            AuthorSource::Config => {}
+            AuthorSource::Env => {
+                if let (Ok(name), Ok(email)) = (
+                    std::env::var("GIT_AUTHOR_NAME"),
+                    std::env::var("GIT_AUTHOR_EMAIL"),
+                ) {
+                    return Ok((name, email));
+                }
+                debug!("GIT_AUTHOR_NAME or GIT_AUTHOR_EMAIL is not set, using the git config");
+            }
+            AuthorSource::LastCommit => {
+                if let Ok(head) = self
+                    .git_repo()
+                    .head()
+                    .and_then(|head| head.peel_to_commit())
+                {
+                    let author = head.author();
+                    if let (Some(name), Some(email)) = (author.name(), author.email()) {
+                        return Ok((name.to_owned(), email.to_owned()));
+                    }
+                }
+                debug!("HEAD has no usable author, using the git config");
+            }
+        }
+
+        // The repository config includes the global, XDG and system levels,
+        // so an identity configured for the whole machine is used when the
+        // repository doesn't set one itself.
+        let config = self.git_repo().config()?.snapshot()?;
+        Ok((
+            get_identity(&config, "user.name")?.to_owned(),
+            get_identity(&config, "user.email")?.to_owned(),
+        ))
+    }
+
+    fn signature(&self, name: &str, email: &str) -> GitResult<git2::Signature<'static>> {
+        let now = git2::Signature::now(name, email)?;
+        if self.options.commit_time.is_none() && self.options.utc_offset.is_none() {
+            return Ok(now);
+        }
+        let time = git2::Time::new(
+            self.options.commit_time.unwrap_or(now.when().seconds()),
+            self.options
+                .utc_offset
+                .unwrap_or(now.when().offset_minutes()),
         );
-        remote.fetch(&[self.branch()], Some(&mut fetch_options), None)?;
+        git2::Signature::new(name, email, &time)
+    }
+
+    /// A signature of `user.name` and `user.email`, for the commits and
+    /// tags that aren't sync commits.
+    fn config_signature(&self) -> GitResult<git2::Signature<'static>> {
+        let identity = self.git_repo().signature()?;
+        self.signature(
+            identity.name().unwrap_or_default(),
+            identity.email().unwrap_or_default(),
+        )
+    }
 
-        let fetch_head = self.git_repo.find_reference(FETCH_HEAD)?;
-        let commit = self.git_repo.reference_to_annotated_commit(&fetch_head)?;
-        Ok(commit)
+    /// The unix time of new commits.
+    fn now(&self) -> GitResult<i64> {
+        match self.options.commit_time {
+            Some(time) => Ok(time),
+            None => unix_now(),
+        }
     }
 
-    pub fn merge(&self, remote: git2::AnnotatedCommit) -> GitResult<()> {
+    /// Fetches the remote branch or the configured refspecs and returns the
+    /// commit to merge, `None` if the remote branch doesn't exist yet.
+    pub fn fetch(&self) -> GitResult<Option<git2::Oid>> {
+        self.get_remote()?;
+        let default_refspec = self.tracking_refspec(self.remote_branch());
+        let refspecs: Vec<&str> = if self.options.fetch_refspecs.is_empty() {
+            vec![&default_refspec]
+        } else {
+            self.options
+                .fetch_refspecs
+                .iter()
+                .map(String::as_str)
+                .collect()
+        };
+        self.backend.fetch(
+            self.remote(),
+            &refspecs,
+            self.remote_branch(),
+            &self.options.credentials,
+        )
+    }
+
+    /// The refspec to fetch `branch` into its remote-tracking branch.
+    fn tracking_refspec(&self, branch: &str) -> String {
+        format!(
+            "+refs/heads/{}:refs/remotes/{}/{}",
+            branch,
+            self.remote(),
+            branch
+        )
+    }
+
+    pub fn merge(&self, remote: git2::Oid) -> GitResult<()> {
         info!("Let's do a merge");
-        let analysis = self.git_repo.merge_analysis(&[&remote])?;
+        let annotated = self.git_repo().find_annotated_commit(remote)?;
+        let analysis = self.git_repo().merge_analysis(&[&annotated])?;
+
+        if self.options.verify_signatures && !analysis.0.is_up_to_date() {
+            signature::verify_commit(self.git_repo(), remote, self.options.keyring.as_deref())?;
+        }
+
+        // An unborn branch, e.g. in a freshly initialized repository, has no
+        // commit to merge into, so it just starts at the fetched commit.
+        if analysis.0.is_unborn() {
+            info!(
+                "{} has no commits yet, checking out {}/{}",
+                self.branch(),
+                self.remote(),
+                self.remote_branch()
+            );
+            return self.do_initial_checkout(remote);
+        }
+
+        let fast_forward =
+            analysis.0.is_fast_forward() && self.options.merge_mode != MergeMode::NoFastForward;
 
-        if analysis.0.is_fast_forward() {
+        if fast_forward {
             info!("Merging with Fastforward");
             self.do_fast_forward(remote)?;
+            self.run_post_hook("post-merge", &["0"]);
         } else if analysis.0.is_normal() {
+            if self.options.merge_mode == MergeMode::FastForwardOnly {
+                return Err(git2::Error::new(
+                    git2::ErrorCode::NotFastForward,
+                    git2::ErrorClass::Merge,
+                    format!(
+                        "{}/{} can't be fast-forwarded and --ff-only is set",
+                        self.remote(),
+                        self.remote_branch()
+                    ),
+                ));
+            }
+
             info!("Do a normal merge");
             // search the local commit
             // TODO: Better git2 error
@@ -126,91 +648,567 @@ impl<'a> RepoInformation<'a> {
                 git2::ErrorClass::Object,
                 "Some git2 error occured",
             ))?;
-            let local = self.git_repo().find_annotated_commit(local_oid)?;
-            self.do_normal_merge(remote, local)?;
+            let msg = self.merge_message(local_oid, remote)?;
+            let sig = self.config_signature()?;
+            if self.backend.merge(local_oid, remote, &msg, &sig)?.is_none() {
+                return Err(git2::Error::new(
+                    git2::ErrorCode::Conflict,
+                    git2::ErrorClass::Merge,
+                    format!(
+                        "merging {}/{} produced conflicts, they are left in the working tree",
+                        self.remote(),
+                        self.remote_branch()
+                    ),
+                ));
+            }
+            self.run_post_hook("post-merge", &["0"]);
         } else {
             info!("There is nothing to do");
         }
         Ok(())
     }
 
+    /// Stashes the working changes, including untracked files.
+    ///
+    /// Returns `false` if there was nothing to stash.
+    pub fn stash(&mut self) -> GitResult<bool> {
+        info!("Stashing local changes in {}", self.path());
+        let signature = self.git_repo().signature()?;
+        match self.backend.repository_mut().stash_save(
+            &signature,
+            "git-sync: stash before merge",
+            Some(git2::StashFlags::INCLUDE_UNTRACKED),
+        ) {
+            Ok(_) => Ok(true),
+            Err(e) if e.code() == git2::ErrorCode::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Restores the most recent stash and drops it.
+    ///
+    /// If applying the stash produces conflicts, the conflicts are left in the
+    /// index and working tree, the stash is kept and an error with
+    /// `ErrorCode::Conflict` is returned.
+    pub fn stash_pop(&mut self) -> GitResult<()> {
+        info!("Restoring stashed changes in {}", self.path());
+        self.backend.repository_mut().stash_apply(0, None)?;
+
+        if self.git_repo().index()?.has_conflicts() {
+            return Err(git2::Error::new(
+                git2::ErrorCode::Conflict,
+                git2::ErrorClass::Stash,
+                "Restoring the stashed changes produced conflicts, the stash was kept",
+            ));
+        }
+        self.backend.repository_mut().stash_drop(0)
+    }
+
     pub fn push(&self) -> GitResult<()> {
         info!("Perform push request");
-        // TODO: One place to retrieve callbacks
-        let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, _allowed_types| {
-            info!("Ask agent for SSH key");
-            git2::Cred::ssh_key_from_agent(username_from_url.unwrap())
-        });
-        let mut push_options = git2::PushOptions::new();
-        push_options.remote_callbacks(callbacks);
-
-        let mut remote = self.get_remote();
-        // TODO: Not a static refspec
-        remote.push(
-            &["refs/heads/master:refs/heads/master"],
-            Some(&mut push_options),
+        let mut remote = self.get_remote()?;
+        let refspecs = if self.options.mirror {
+            self.mirror_refspecs(&mut remote)?
+        } else {
+            self.branch_refspecs(&mut remote)?
+        };
+        self.push_refspecs(&refspecs)?;
+
+        if self.options.mirror {
+            Ok(())
+        } else {
+            self.track_upstream()
+        }
+    }
+
+    fn push_refspecs(&self, refspecs: &[String]) -> GitResult<()> {
+        self.backend
+            .push(self.remote(), refspecs, &self.options.credentials)
+    }
+
+    /// Tags HEAD with an annotated tag named after `tag_pattern`, unless the
+    /// last tag was created less than `tag_interval` ago.
+    ///
+    /// `{timestamp}` in the pattern is replaced by the current unix time and
+    /// `{branch}` by the branch. If a tag of that name exists, a counter is
+    /// appended, e.g. `sync-1600000000-1`.
+    pub fn tag_sync(&mut self) -> GitResult<()> {
+        let pattern = match &self.options.tag_pattern {
+            Some(pattern) => pattern,
+            None => return Ok(()),
+        };
+        if let (Some(last_tag), Some(interval)) = (self.last_tag, self.options.tag_interval) {
+            if last_tag.elapsed() < interval {
+                debug!("The last tag is less than {:?} old, not tagging", interval);
+                return Ok(());
+            }
+        }
+
+        let base_name = pattern
+            .replace("{timestamp}", &self.now()?.to_string())
+            .replace("{branch}", self.branch());
+        let mut name = base_name.clone();
+        let mut counter = 0;
+        while self
+            .git_repo()
+            .find_reference(&format!("refs/tags/{}", name))
+            .is_ok()
+        {
+            counter += 1;
+            name = format!("{}-{}", base_name, counter);
+        }
+
+        let message = format!("git-sync checkpoint of {}", self.branch());
+        let head = self.git_repo().head()?.peel(git2::ObjectType::Commit)?;
+        self.git_repo()
+            .tag(&name, &head, &self.config_signature()?, &message, false)?;
+        info!("Tagged {} as {}", self.short_id(head.id())?, name);
+        drop(head);
+        self.last_tag = Some(Instant::now());
+
+        if self.options.push_tags {
+            self.push_refspecs(&[format!("refs/tags/{}:refs/tags/{}", name, name)])?;
+        }
+        Ok(())
+    }
+
+    /// Syncs `branch`, which is not checked out, with the branch of the same
+    /// name on the remote.
+    ///
+    /// Without a working tree to merge in, the branch is only fast-forwarded
+    /// if the remote is ahead or pushed if it's ahead itself. A branch that
+    /// diverged from the remote is left alone with a warning.
+    pub fn sync_branch(&self, branch: &str) -> GitResult<()> {
+        if current_branch(self.git_repo()).as_deref() == Some(branch) {
+            return Err(git2::Error::from_str(&format!(
+                "{} is checked out, only the synced branch may be",
+                branch
+            )));
+        }
+
+        self.get_remote()?;
+        self.backend.fetch(
+            self.remote(),
+            &[&self.tracking_refspec(branch)],
+            branch,
+            &self.options.credentials,
         )?;
+
+        let local_ref = format!("refs/heads/{}", branch);
+        let push_refspec = format!("{}:{}", local_ref, local_ref);
+        let remote_ref = format!("refs/remotes/{}/{}", self.remote(), branch);
+        let remote_oid = match self.git_repo().refname_to_id(&remote_ref) {
+            Ok(oid) => oid,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                info!(
+                    "{} doesn't exist on {} yet, pushing it",
+                    branch,
+                    self.remote()
+                );
+                return self.push_refspecs(&[push_refspec]);
+            }
+            Err(e) => return Err(e),
+        };
+        let local_oid = match self.git_repo().refname_to_id(&local_ref) {
+            Ok(oid) => oid,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => {
+                info!("Creating {} from {}", branch, remote_ref);
+                self.git_repo().reference(
+                    &local_ref,
+                    remote_oid,
+                    false,
+                    "git-sync: create branch",
+                )?;
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+
+        match self.git_repo().graph_ahead_behind(local_oid, remote_oid)? {
+            (0, 0) => debug!("{} is up to date", branch),
+            (0, _) => {
+                if self.options.verify_signatures {
+                    signature::verify_commit(
+                        self.git_repo(),
+                        remote_oid,
+                        self.options.keyring.as_deref(),
+                    )?;
+                }
+                info!("Fast-forwarding {} to {}", branch, remote_ref);
+                self.git_repo()
+                    .find_reference(&local_ref)?
+                    .set_target(remote_oid, "git-sync: fast-forward")?;
+            }
+            (_, 0) => {
+                info!("Pushing {}", branch);
+                self.push_refspecs(&[push_refspec])?;
+            }
+            (ahead, behind) => warn!(
+                "{} is {} commit(s) ahead and {} behind {}, not syncing it",
+                branch, ahead, behind, remote_ref
+            ),
+        }
         Ok(())
     }
 
-    fn do_fast_forward(&self, commit: git2::AnnotatedCommit) -> GitResult<()> {
+    /// The refspecs to push the branch with, depending on `push.default`.
+    fn branch_refspecs(&self, remote: &mut git2::Remote) -> GitResult<Vec<String>> {
+        let config = self.git_repo().config()?.snapshot()?;
+        let push_default = match config.get_string("push.default") {
+            Ok(value) => value.parse().map_err(|e: String| {
+                git2::Error::new(git2::ErrorCode::Invalid, git2::ErrorClass::Config, e)
+            })?,
+            Err(e) if e.code() == git2::ErrorCode::NotFound => PushDefault::default(),
+            Err(e) => return Err(e),
+        };
+
+        let local_ref = format!("refs/heads/{}", self.branch());
+        let remote_ref = format!("refs/heads/{}", self.remote_branch());
+        match push_default {
+            PushDefault::Simple | PushDefault::Upstream => {
+                Ok(vec![format!("{}:{}", local_ref, remote_ref)])
+            }
+            PushDefault::Current => Ok(vec![format!("{}:{}", local_ref, local_ref)]),
+            PushDefault::Matching => {
+                let mut refspecs = vec![format!("{}:{}", local_ref, remote_ref)];
+                remote.connect_auth(
+                    git2::Direction::Push,
+                    Some(self.options.credentials.callbacks()),
+                    None,
+                )?;
+                for head in remote.list()? {
+                    let name = head.name();
+                    if name.starts_with("refs/heads/")
+                        && name != remote_ref
+                        && self.git_repo().find_reference(name).is_ok()
+                    {
+                        refspecs.push(format!("{}:{}", name, name));
+                    }
+                }
+                remote.disconnect()?;
+                Ok(refspecs)
+            }
+            PushDefault::Nothing => Err(git2::Error::new(
+                git2::ErrorCode::Invalid,
+                git2::ErrorClass::Config,
+                "push.default is \"nothing\", refusing to push",
+            )),
+        }
+    }
+
+    /// The refspecs to push all local refs and delete the stale ones on
+    /// the remote, the equivalent of `+refs/*:refs/*` with pruning. libgit2
+    /// can't push wildcard refspecs, so every ref is listed on its own.
+    fn mirror_refspecs(&self, remote: &mut git2::Remote) -> GitResult<Vec<String>> {
+        let mut refspecs = Vec::new();
+        for reference in self.git_repo().references()? {
+            let reference = reference?;
+            if reference.kind() != Some(git2::ReferenceType::Direct) {
+                continue;
+            }
+            if let Some(name) = reference.name() {
+                refspecs.push(format!("+{}:{}", name, name));
+            }
+        }
+        remote.connect_auth(
+            git2::Direction::Push,
+            Some(self.options.credentials.callbacks()),
+            None,
+        )?;
+        for head in remote.list()? {
+            let name = head.name();
+            if name.starts_with("refs/") && self.git_repo().find_reference(name).is_err() {
+                info!("Deleting {} on the remote", name);
+                refspecs.push(format!(":{}", name));
+            }
+        }
+        remote.disconnect()?;
+        Ok(refspecs)
+    }
+
+    /// Configures the synced remote branch as upstream of the local branch,
+    /// so a plain `git pull` in the repository works as well.
+    fn track_upstream(&self) -> GitResult<()> {
+        let remote_key = format!("branch.{}.remote", self.branch());
+        let merge_key = format!("branch.{}.merge", self.branch());
+        let mut config = self.git_repo().config()?;
+        let configured = config.get_entry(&remote_key).is_ok();
+        match self.options.set_upstream {
+            Some(false) => return Ok(()),
+            None if configured => return Ok(()),
+            _ => {}
+        }
+
+        info!(
+            "Setting upstream of {} to {}/{}",
+            self.branch(),
+            self.remote(),
+            self.remote_branch()
+        );
+        config.set_str(&remote_key, self.remote())?;
+        config.set_str(&merge_key, &format!("refs/heads/{}", self.remote_branch()))
+    }
+
+    /// Moves the branch back to `oid`, keeping the changes of the dropped
+    /// commits in the working tree, unstaged, so the next sync commits them
+    /// again.
+    pub fn reset_mixed(&self, oid: git2::Oid) -> GitResult<()> {
+        info!("Resetting {} to {}", self.branch(), oid);
+        let commit = self
+            .git_repo()
+            .find_object(oid, Some(git2::ObjectType::Commit))?;
+        self.git_repo().reset(&commit, git2::ResetType::Mixed, None)
+    }
+
+    fn do_fast_forward(&self, oid: git2::Oid) -> GitResult<()> {
         let refname = format!("refs/heads/{}", self.branch());
-        let mut refe = self.git_repo.find_reference(&refname)?;
+        let mut refe = self.git_repo().find_reference(&refname)?;
 
+        // Like for a merge, the working tree is updated while HEAD still
+        // points to the local commit, which keeps uncommitted changes and
+        // fails instead of overwriting them.
+        let commit = self.git_repo().find_commit(oid)?;
+        self.git_repo().checkout_tree(commit.as_object(), None)?;
         // TODO: Better reflog message
-        refe.set_target(commit.id(), "Fast-Forward")?;
-        self.git_repo.set_head(refe.name().unwrap())?;
-        self.git_repo
-            .checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
-        Ok(())
+        refe.set_target(oid, "Fast-Forward")?;
+        self.git_repo().set_head(&refname)
     }
 
-    fn do_normal_merge(
-        &self,
-        local: git2::AnnotatedCommit,
-        remote: git2::AnnotatedCommit,
-    ) -> Result<(), git2::Error> {
-        unimplemented!();
-        let local_tree = self.git_repo().find_commit(local.id())?.tree()?;
-        let remote_tree = self.git_repo().find_commit(remote.id())?.tree()?;
-        let ancestor = self
-            .git_repo()
-            .find_commit(self.git_repo().merge_base(local.id(), remote.id())?)?
-            .tree()?;
-        let mut idx = self
-            .git_repo()
-            .merge_trees(&ancestor, &local_tree, &remote_tree, None)?;
+    /// Creates the unborn branch at `oid` and checks it out. Files that
+    /// already exist in the working tree are not overwritten, the checkout
+    /// fails instead.
+    fn do_initial_checkout(&self, oid: git2::Oid) -> GitResult<()> {
+        let refname = format!("refs/heads/{}", self.branch());
+        self.git_repo()
+            .reference(&refname, oid, false, "git-sync: initial checkout")?;
+        self.git_repo().set_head(&refname)?;
+        self.git_repo().checkout_head(None)
+    }
+
+    fn merge_message(&self, local: git2::Oid, remote: git2::Oid) -> GitResult<String> {
+        let template = self
+            .options
+            .merge_message_template
+            .as_deref()
+            .unwrap_or(DEFAULT_MERGE_MESSAGE_TEMPLATE);
+
+        Ok(template
+            .replace("{remote}", &self.short_id(remote)?)
+            .replace("{local}", &self.short_id(local)?)
+            .replace("{branch}", self.branch())
+            .replace("{timestamp}", &self.now()?.to_string()))
+    }
+
+    /// Looks up the remote, creating it if it doesn't exist and an url is
+    /// configured for it.
+    fn get_remote(&self) -> GitResult<git2::Remote<'_>> {
+        match self.git_repo().find_remote(self.remote()) {
+            Err(e) if e.code() == git2::ErrorCode::NotFound => match &self.options.remote_url {
+                Some(url) => {
+                    info!(
+                        "Remote {} doesn't exist, creating it for {}",
+                        self.remote(),
+                        url
+                    );
+                    self.git_repo().remote(self.remote(), url)
+                }
+                None => Err(e),
+            },
+            result => result,
+        }
+    }
+}
+
+/// Whether a failed network operation may succeed when retried later.
+pub fn is_transient(error: &git2::Error) -> bool {
+    matches!(
+        error.class(),
+        git2::ErrorClass::Net
+            | git2::ErrorClass::Ssh
+            | git2::ErrorClass::Http
+            | git2::ErrorClass::Os
+    )
+}
+
+/// Whether `error` may come from a repository handle that is out of date,
+/// e.g. because `git gc` removed packs or the repository was cloned again.
+pub fn is_stale(error: &git2::Error) -> bool {
+    matches!(
+        error.class(),
+        git2::ErrorClass::Odb | git2::ErrorClass::Repository | git2::ErrorClass::Index
+    )
+}
+
+/// The name of the checked out branch, `None` for a detached or unborn HEAD.
+pub fn current_branch(repo: &git2::Repository) -> Option<String> {
+    let head = repo.head().ok()?;
+    if !head.is_branch() {
+        return None;
+    }
+    head.shorthand().map(str::to_owned)
+}
+
+/// Returns the host and port git connects to for `url`, or `None` for local
+/// remotes.
+pub fn remote_address(url: &str) -> Option<(String, u16)> {
+    let (authority, default_port) = match url.split_once("://") {
+        Some((scheme, rest)) => {
+            let port = match scheme {
+                "ssh" | "git+ssh" | "ssh+git" => 22,
+                "git" => 9418,
+                "http" => 80,
+                "https" => 443,
+                _ => return None,
+            };
+            (rest.split('/').next()?, port)
+        }
+        None => {
+            // scp-like syntax "[user@]host:path". Local paths have no colon
+            // before the first slash, except for Windows drive letters.
+            let (host, _) = url.split_once(':')?;
+            if host.contains('/') || host.len() == 1 {
+                return None;
+            }
+            return Some((host.rsplit('@').next()?.to_owned(), 22));
+        }
+    };
+
+    let authority = authority.rsplit('@').next()?;
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        // IPv6 literal like "[::1]:22"
+        let (host, rest) = rest.split_once(']')?;
+        (host, rest.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(port) => port.parse().ok()?,
+        None => default_port,
+    };
+    Some((host.to_owned(), port))
+}
+
+fn unix_now() -> GitResult<i64> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    Ok(now.as_secs() as i64)
+}
 
-        if idx.has_conflicts() {
-            info!("Merge conficts detected...");
-            self.git_repo().checkout_index(Some(&mut idx), None)?;
-            return Ok(());
+fn get_identity<'c>(config: &'c git2::Config, key: &str) -> GitResult<&'c str> {
+    config.get_str(key).map_err(|e| {
+        if e.code() == git2::ErrorCode::NotFound {
+            git2::Error::new(
+                git2::ErrorCode::NotFound,
+                git2::ErrorClass::Config,
+                format!(
+                    "{} is neither set in the repository nor in the global git config",
+                    key
+                ),
+            )
+        } else {
+            e
         }
-        let result_tree = self
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+
+    use super::{Credentials, SyncOptions};
+    use crate::testutil::TestRepo;
+
+    /// The request line and `Authorization` header of requests.
+    type Requests = Arc<Mutex<Vec<(String, String)>>>;
+
+    /// Serves HTTP on a local port, asking for basic authentication and
+    /// failing every authenticated request. Returns the url and the
+    /// authenticated requests.
+    fn serve_auth_only() -> (String, Requests) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/repo.git", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&requests);
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(stream.try_clone().unwrap()).lines();
+                let request = lines.next().unwrap().unwrap();
+                let authorization = lines
+                    .map(Result::unwrap)
+                    .take_while(|line| !line.is_empty())
+                    .find_map(|line| line.strip_prefix("Authorization: ").map(str::to_owned));
+                let response = match authorization {
+                    Some(authorization) => {
+                        seen.lock().unwrap().push((request, authorization));
+                        "HTTP/1.1 404 Not Found\r\n"
+                    }
+                    None => {
+                        "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Basic realm=\"git\"\r\n"
+                    }
+                };
+                let _ = write!(
+                    stream,
+                    "{}Content-Length: 0\r\nConnection: close\r\n\r\n",
+                    response
+                );
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn fetch_and_push_authenticate_with_the_token() {
+        let (url, requests) = serve_auth_only();
+        let test_repo = TestRepo::new();
+        let mut repo_information = test_repo.repo_information();
+        repo_information
             .git_repo()
-            .find_tree(idx.write_tree_to(self.git_repo())?)?;
-        // now create the merge commit
-        let msg = format!("Merge: {} into {}", remote.id(), local.id());
-        let sig = self.git_repo().signature()?;
-        let local_commit = self.git_repo().find_commit(local.id())?;
-        let remote_commit = self.git_repo().find_commit(remote.id())?;
-        // Do our merge commit and set current branch head to that commit.
-        let _merge_commit = self.git_repo().commit(
-            Some("HEAD"),
-            &sig,
-            &sig,
-            &msg,
-            &result_tree,
-            &[&local_commit, &remote_commit],
-        )?;
-        // Set working tree to match head.
-        self.git_repo().checkout_head(None)?;
-        Ok(())
+            .remote_set_url("origin", &url)
+            .unwrap();
+        repo_information.set_options(SyncOptions {
+            credentials: Credentials::Token("secret".to_owned()),
+            ..repo_information.options().clone()
+        });
+
+        assert!(repo_information.fetch().is_err());
+        assert!(repo_information.push().is_err());
+
+        let requests = requests.lock().unwrap();
+        let services: Vec<&str> = requests
+            .iter()
+            .map(|(request, authorization)| {
+                assert_eq!(authorization, "Basic Z2l0OnNlY3JldA==");
+                if request.contains("git-upload-pack") {
+                    "fetch"
+                } else {
+                    "push"
+                }
+            })
+            .collect();
+        assert!(services.contains(&"fetch"));
+        assert!(services.contains(&"push"));
     }
 
-    fn get_remote(&self) -> git2::Remote {
-        // TODO: Proper error handeling
-        self.git_repo.find_remote(self.remote()).unwrap()
+    #[test]
+    fn commits_at_a_fixed_time_are_reproducible() {
+        let commit = || {
+            let test_repo = TestRepo::new();
+            test_repo.write("a", "a\n");
+            let repo_information = test_repo.repo_information();
+            repo_information
+                .backend()
+                .stage(&[Path::new("a")], &[])
+                .unwrap();
+            repo_information.commit("Add a").unwrap()
+        };
+        assert_eq!(commit(), commit());
     }
 }
@@ -0,0 +1,75 @@
+//! Stopping the watcher on SIGTERM and SIGINT.
+//!
+//! Instead of being killed, git-sync finishes the sync it's running and
+//! returns from the watch loop, so the pid file and the repository lock are
+//! cleaned up. On other platforms than Unix, the signals keep their default
+//! behaviour.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+mod imp {
+    use std::mem::MaybeUninit;
+    use std::thread;
+
+    use super::{Ordering, REQUESTED};
+
+    pub fn on_signal<F: FnOnce() + Send + 'static>(wake: F) {
+        // The signals are blocked here and in all threads spawned later on,
+        // so only the thread waiting for them receives them.
+        let set = unsafe {
+            let mut set = MaybeUninit::<libc::sigset_t>::uninit();
+            libc::sigemptyset(set.as_mut_ptr());
+            libc::sigaddset(set.as_mut_ptr(), libc::SIGTERM);
+            libc::sigaddset(set.as_mut_ptr(), libc::SIGINT);
+            set.assume_init()
+        };
+        let ret = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) };
+        if ret != 0 {
+            warn!(
+                "Failed to block SIGTERM and SIGINT, they stop git-sync right away: {}",
+                std::io::Error::from_raw_os_error(ret)
+            );
+            return;
+        }
+
+        thread::spawn(move || {
+            let mut signal = 0;
+            let ret = unsafe { libc::sigwait(&set, &mut signal) };
+            if ret != 0 {
+                warn!(
+                    "Failed to wait for SIGTERM and SIGINT: {}",
+                    std::io::Error::from_raw_os_error(ret)
+                );
+                return;
+            }
+            info!(
+                "Received signal {}, stopping after the current sync",
+                signal
+            );
+            REQUESTED.store(true, Ordering::SeqCst);
+            wake();
+        });
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    pub fn on_signal<F: FnOnce() + Send + 'static>(_wake: F) {}
+}
+
+/// Calls `wake` once SIGTERM or SIGINT is received, after which `requested`
+/// returns `true`.
+///
+/// This has to be called before any other threads are spawned. A signal
+/// delivered to one of those would still terminate git-sync right away.
+pub fn on_signal<F: FnOnce() + Send + 'static>(wake: F) {
+    imp::on_signal(wake);
+}
+
+/// Whether git-sync was asked to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,112 @@
+//! Signing sync commits and verifying the GPG signatures of fetched commits.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::repository::GitResult;
+
+/// Checks that `oid` carries a good GPG signature.
+///
+/// The signature is checked with `gpg`, using `keyring` instead of the
+/// default keyring if given.
+pub fn verify_commit(
+    repo: &git2::Repository,
+    oid: git2::Oid,
+    keyring: Option<&Path>,
+) -> GitResult<()> {
+    let (signature, data) = match repo.extract_signature(&oid, None) {
+        Ok(extracted) => extracted,
+        Err(e) if e.code() == git2::ErrorCode::NotFound => {
+            return Err(verification_error(format!("commit {} is not signed", oid)));
+        }
+        Err(e) => return Err(e),
+    };
+
+    // A file of its own that nobody else can replace, e.g. with a symlink.
+    let signature_file = tempfile::Builder::new()
+        .prefix("git-sync-")
+        .suffix(".sig")
+        .tempfile()
+        .and_then(|mut file| file.write_all(&signature).map(|()| file))
+        .map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    let mut command = Command::new("gpg");
+    command.args(["--batch", "--status-fd", "1"]);
+    if let Some(keyring) = keyring {
+        command
+            .arg("--no-default-keyring")
+            .arg("--keyring")
+            .arg(keyring);
+    }
+    let mut gpg = command
+        .arg("--verify")
+        .arg(signature_file.path())
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| verification_error(format!("failed to run gpg: {}", e)))?;
+
+    gpg.stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(&data)
+        .map_err(|e| verification_error(format!("failed to run gpg: {}", e)))?;
+    let output = gpg
+        .wait_with_output()
+        .map_err(|e| verification_error(format!("failed to run gpg: {}", e)))?;
+
+    let status = String::from_utf8_lossy(&output.stdout);
+    let has = |keyword: &str| {
+        status
+            .lines()
+            .any(|line| line.split_whitespace().nth(1) == Some(keyword))
+    };
+    if output.status.success() && has("GOODSIG") && has("VALIDSIG") {
+        debug!("Commit {} has a good signature", oid);
+        Ok(())
+    } else {
+        Err(verification_error(format!(
+            "commit {} does not have a valid signature",
+            oid
+        )))
+    }
+}
+
+/// Creates an armored detached signature of `data` with the GPG key
+/// `signing_key`, like `git commit -S` does.
+pub fn sign(data: &[u8], signing_key: &str) -> GitResult<String> {
+    let mut gpg = Command::new("gpg")
+        .args(["--batch", "--status-fd", "2", "-bsau", signing_key])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| signing_error(format!("failed to run gpg: {}", e)))?;
+
+    gpg.stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(data)
+        .map_err(|e| signing_error(format!("failed to run gpg: {}", e)))?;
+    let output = gpg
+        .wait_with_output()
+        .map_err(|e| signing_error(format!("failed to run gpg: {}", e)))?;
+
+    match String::from_utf8(output.stdout) {
+        Ok(signature) if output.status.success() && !signature.is_empty() => Ok(signature),
+        _ => Err(signing_error(format!(
+            "gpg failed to sign with key {}",
+            signing_key
+        ))),
+    }
+}
+
+fn signing_error(msg: String) -> git2::Error {
+    git2::Error::new(git2::ErrorCode::GenericError, git2::ErrorClass::Object, msg)
+}
+
+fn verification_error(msg: String) -> git2::Error {
+    git2::Error::new(git2::ErrorCode::GenericError, git2::ErrorClass::Merge, msg)
+}
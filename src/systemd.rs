@@ -0,0 +1,65 @@
+//! Readiness and watchdog notifications for running under systemd with
+//! `Type=notify`.
+//!
+//! Without the `sd-notify` feature, or on other platforms than Linux, all of
+//! these are no-ops.
+
+use std::time::Duration;
+
+#[cfg(all(feature = "sd-notify", target_os = "linux"))]
+mod imp {
+    use sd_notify::NotifyState;
+    use std::time::Duration;
+
+    pub fn notify_ready() {
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Ready]) {
+            warn!("Failed to notify systemd about readiness: {}", e);
+        }
+    }
+
+    pub fn notify_watchdog() {
+        if let Err(e) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+            warn!("Failed to send watchdog keep-alive to systemd: {}", e);
+        }
+    }
+
+    pub fn watchdog_timeout() -> Option<Duration> {
+        let mut usec = 0;
+        if sd_notify::watchdog_enabled(false, &mut usec) {
+            Some(Duration::from_micros(usec))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(not(all(feature = "sd-notify", target_os = "linux")))]
+mod imp {
+    use std::time::Duration;
+
+    pub fn notify_ready() {}
+
+    pub fn notify_watchdog() {}
+
+    pub fn watchdog_timeout() -> Option<Duration> {
+        None
+    }
+}
+
+/// Tells systemd that git-sync is up and watching.
+pub fn notify_ready() {
+    imp::notify_ready();
+}
+
+/// Sends a watchdog keep-alive to systemd.
+pub fn notify_watchdog() {
+    imp::notify_watchdog();
+}
+
+/// How often `notify_watchdog` has to be called, if systemd has the watchdog
+/// enabled for this service.
+///
+/// This is half of `WatchdogSec`, as recommended by `sd_watchdog_enabled(3)`.
+pub fn watchdog_interval() -> Option<Duration> {
+    imp::watchdog_timeout().map(|timeout| timeout / 2)
+}
@@ -0,0 +1,119 @@
+//! Fixtures for tests that sync against a remote without network access.
+
+use std::path::Path;
+
+use tempfile::TempDir;
+
+use crate::repository::{RepoInformation, SyncOptions};
+
+/// The time of every commit made by the fixtures, so commit ids are the
+/// same in every run.
+pub static COMMIT_TIME: i64 = 1_600_000_000;
+
+/// A working repository on `master` and a bare repository it syncs with
+/// as `origin`, both seeded with an initial commit. The repositories live
+/// in a temporary directory, which is removed on drop.
+pub struct TestRepo {
+    dir: TempDir,
+    work: String,
+}
+
+impl TestRepo {
+    pub fn new() -> Self {
+        let dir = TempDir::new().expect("failed to create a temporary directory");
+        let remote_path = dir.path().join("remote.git");
+        let work_path = dir.path().join("work");
+
+        let mut options = git2::RepositoryInitOptions::new();
+        options.initial_head("master");
+        git2::Repository::init_opts(&remote_path, options.bare(true)).unwrap();
+        let repo = git2::Repository::init_opts(&work_path, options.bare(false)).unwrap();
+
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        repo.remote("origin", remote_path.to_str().unwrap())
+            .unwrap();
+
+        let test_repo = Self {
+            work: work_path.to_str().unwrap().to_owned(),
+            dir,
+        };
+        test_repo.write("README", "initial\n");
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("README")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature =
+            git2::Signature::new("Test", "test@example.com", &git2::Time::new(COMMIT_TIME, 0))
+                .unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "init", &tree, &[])
+            .unwrap();
+        repo.find_remote("origin")
+            .unwrap()
+            .push(&["refs/heads/master:refs/heads/master"], None)
+            .unwrap();
+        test_repo
+    }
+
+    /// Syncs the working repository with `origin/master`, committing at
+    /// `COMMIT_TIME` in UTC.
+    pub fn repo_information(&self) -> RepoInformation<'_> {
+        let mut repo_information = RepoInformation::new(&self.work, "origin", "master");
+        repo_information.set_options(SyncOptions {
+            commit_time: Some(COMMIT_TIME),
+            utc_offset: Some(0),
+            ..SyncOptions::default()
+        });
+        repo_information
+    }
+
+    /// Writes `content` to `path` in the working tree, creating missing
+    /// parent directories.
+    pub fn write(&self, path: &str, content: &str) {
+        let path = Path::new(&self.work).join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    /// Commits `content` as `path`, a file in the root of the tree, on top
+    /// of `master` on the remote, as if another machine pushed it.
+    pub fn commit_on_remote(&self, path: &str, content: &str) -> git2::Oid {
+        let remote = self.remote();
+        let parent = remote.find_commit(self.remote_head()).unwrap();
+        let blob = remote.blob(content.as_bytes()).unwrap();
+        let mut builder = remote.treebuilder(Some(&parent.tree().unwrap())).unwrap();
+        builder.insert(path, blob, 0o100644).unwrap();
+        let tree = remote.find_tree(builder.write().unwrap()).unwrap();
+        let signature = git2::Signature::new(
+            "Other",
+            "other@example.com",
+            &git2::Time::new(COMMIT_TIME, 0),
+        )
+        .unwrap();
+        remote
+            .commit(
+                Some("refs/heads/master"),
+                &signature,
+                &signature,
+                &format!("Change {} elsewhere", path),
+                &tree,
+                &[&parent],
+            )
+            .unwrap()
+    }
+
+    /// Reads `path` from the working tree.
+    pub fn read(&self, path: &str) -> String {
+        std::fs::read_to_string(Path::new(&self.work).join(path)).unwrap()
+    }
+
+    pub fn remote(&self) -> git2::Repository {
+        git2::Repository::open_bare(self.dir.path().join("remote.git")).unwrap()
+    }
+
+    /// The commit `master` points to on the remote.
+    pub fn remote_head(&self) -> git2::Oid {
+        self.remote().refname_to_id("refs/heads/master").unwrap()
+    }
+}
@@ -0,0 +1,115 @@
+//! Limiting how often the watcher reacts to filesystem events.
+
+use std::time::{Duration, Instant};
+
+/// Limits the syncs events trigger to a rate. The changes of events over it
+/// are deferred and synced together once the rate allows it again.
+pub struct EventLimit {
+    rate: u32,
+    bucket: TokenBucket,
+    deferred: bool,
+}
+
+impl EventLimit {
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate,
+            bucket: TokenBucket::new(rate),
+            deferred: false,
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Whether an event may trigger a sync now, otherwise it's deferred.
+    pub fn admit(&mut self) -> bool {
+        self.deferred = !self.bucket.try_acquire();
+        !self.deferred
+    }
+
+    /// Whether the deferred events may be synced now.
+    pub fn deferred_sync_due(&mut self) -> bool {
+        if self.deferred && self.bucket.try_acquire() {
+            self.deferred = false;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the deferred events may be synced, `None` if there are
+    /// none.
+    pub fn until_deferred_sync(&self) -> Option<Duration> {
+        if self.deferred {
+            Some(self.bucket.next_token_in())
+        } else {
+            None
+        }
+    }
+}
+
+/// A token bucket that allows `rate` events per second on average and
+/// bursts of up to `rate` events.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: u32) -> Self {
+        let rate = f64::from(rate);
+        Self {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Takes a token, returns `false` if there is none left.
+    fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until the next token is available.
+    fn next_token_in(&self) -> Duration {
+        let tokens = self.tokens + self.refilled.elapsed().as_secs_f64() * self.rate;
+        if tokens >= 1.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((1.0 - tokens) / self.rate)
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_deferred_events_take_a_token_for_their_sync() {
+        let mut limit = EventLimit::new(2);
+        assert!(limit.admit());
+        assert!(!limit.deferred_sync_due());
+        assert!(limit.admit());
+
+        assert!(!limit.admit());
+        assert!(limit.until_deferred_sync().is_some());
+        assert!(!limit.deferred_sync_due());
+    }
+}